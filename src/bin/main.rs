@@ -3,16 +3,28 @@ extern crate env_logger;
 extern crate log;
 
 use env_logger::Builder;
-use clap::{arg, value_parser, ArgMatches, Command};
-use log::{debug, LevelFilter};
-use phototools::copier::Copier;
+use clap::{arg, value_parser, ArgAction, ArgMatches, Command};
+use log::{debug, warn, LevelFilter};
+use phototools::config::{Config, Value, DESTINATION_CONFIG};
+use phototools::copier::{Copier, SetTimes};
+use phototools::date_range::DateRange;
+use phototools::error::Error;
+use phototools::filename_dates::{FilenameDates, FilenamePattern};
+use phototools::filter::PathFilter;
+use phototools::link::LinkMode;
+use phototools::stats::{CopyStats, PlannedCopy};
+use phototools::template::{Template, DEFAULT_LAYOUT};
+use phototools::timestamp::TimezoneMode;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 
-type GenError = Box<dyn std::error::Error>;
+const DEFAULT_FILESIZE_MIN: i64 = 500;
 
-const DEFAULT_FILESIZE_MIN: &str = "500";
+/// The copy settings that can be set in a configuration file, by their key.
+const COPY_SETTINGS: [&str; 19] = ["min_size", "link_mode", "mode", "layout", "rename", "timezone", "index", "jobs",
+    "fail_fast", "manifest", "date_patterns", "include", "exclude", "include_hidden", "since", "until", "set_times",
+    "preserve_attributes", "keep_original_mtime"];
 
 fn cli() -> Command {
     Command::new("Photo Tools")
@@ -40,13 +52,101 @@ fn cli() -> Command {
                     .value_parser(value_parser!(PathBuf)))
                 .arg(arg!(--"min-size" <BYTES>)
                     .short('b')
-                    .help("When copying only consider photos and videos of at least this size")
-                    .value_parser(value_parser!(u32))
-                    .default_value(DEFAULT_FILESIZE_MIN))
+                    .help("When copying only consider photos and videos of at least this size [default: 500]")
+                    .value_parser(value_parser!(u32)))
+                .arg(arg!(--"link-mode" <MODE>)
+                    .help("How the files in the destination are made: 'copy', 'hard' or 'sym' links to the source \
+                        files, or 'reflink' copies that share their data with the source on filesystems that support \
+                        it, such as Btrfs and XFS, and are normal copies elsewhere. Links get no EXIF dates and keep \
+                        the times of the source [default: copy]"))
                 .arg(arg!(--"cp-copy")
                     .short('c')
-                    .help("Uses 'cp' from the shell to copy files"))
+                    .hide(true)
+                    .help("Deprecated and ignored, files are no longer copied with 'cp'"))
+                .arg(arg!(--"dry-run")
+                    .short('n')
+                    .help("Prints what would be copied where without changing anything"))
+                .arg(arg!(--"move")
+                    .short('m')
+                    .help("Removes each source file once it has been copied and verified, \
+                        or when it is already in the destination"))
+                .arg(arg!(--"copy")
+                    .help("Keeps the source files, when the configuration says to move them")
+                    .conflicts_with("move"))
+                .arg(arg!(--"layout" <TEMPLATE>)
+                    .short('l')
+                    .help("The directory layout in the destination. Tokens: {YYYY} {YY} {MM} {DD} {Q} (quarter) \
+                        {hh} {mm} {ss} {make} {model} {type} (photo or video) {ext} [default: {YYYY}/{YYYY}-{MM}-{DD}]"))
+                .arg(arg!(--"rename" <TEMPLATE>)
+                    .short('r')
+                    .help("Names the copied files using a template, e.g. {date}_{time}_{name}.{ext}. \
+                        Same tokens as --layout plus {date} {time} {camera} {name} and {seq} (sequence number)"))
+                .arg(arg!(--"timezone" <ZONE>)
+                    .short('t')
+                    .help("Which local time decides the folder and file name: 'recorded' (the local time where \
+                        the photo was taken when known, otherwise the time as recorded, which is UTC for videos \
+                        and file modification times), 'local' (this computer's time zone), 'utc' or an offset \
+                        such as +02:00 [default: recorded]"))
+                .arg(arg!(--"no-index")
+                    .help("Don't use the import index in the destination directory to skip files that \
+                        were imported before"))
+                .arg(arg!(--"jobs" <N>)
+                    .short('j')
+                    .help("The number of files to process in parallel [default: 1]")
+                    .value_parser(value_parser!(u32).range(1..)))
+                .arg(arg!(--"fail-fast")
+                    .help("Stops at the first file that cannot be copied, instead of reporting all errors at the end"))
+                .arg(arg!(--"manifest" <FILE>)
+                    .help("Writes a JSON Lines file describing what was done with every source file and why")
+                    .value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--"date-pattern" <REGEX>)
+                    .help("A regular expression that finds the date in the name of files without date metadata, \
+                        with the named groups year, month and day and optionally hour, minute and second. \
+                        Can be repeated, tried before the built-in WhatsApp, Signal, Telegram, Pixel and \
+                        screenshot patterns")
+                    .action(ArgAction::Append))
+                .arg(arg!(--"include" <GLOB>)
+                    .help("Only copies the files that match one of these patterns, e.g. '*.jpg'. Can be repeated. \
+                        A pattern with a '/' matches the path below the source directory, e.g. 'DCIM/**/*.mp4', \
+                        others match the file name. Case is ignored")
+                    .action(ArgAction::Append))
+                .arg(arg!(--"exclude" <GLOB>)
+                    .help("Leaves out the files and directories that match this pattern, e.g. 'cache' or \
+                        'DCIM/.Trash'. Can be repeated")
+                    .action(ArgAction::Append))
+                .arg(arg!(--"include-hidden")
+                    .help("Also copies hidden files and looks into hidden directories: those whose name starts \
+                        with a dot, such as .thumbnails, and Synology's @eaDir"))
+                .arg(arg!(--"since" <DATE>)
+                    .help("Only copies photos and videos taken on or after this date: a year, month, day or time \
+                        such as 2023, 2023-05, 2023-05-01 or '2023-05-01 12:00'. With 'last', only those taken after \
+                        the last import from the same camera into the destination"))
+                .arg(arg!(--"until" <DATE>)
+                    .help("Only copies photos and videos taken on or before this date, which is included entirely: \
+                        --until 2023 includes December 31st"))
+                .arg(arg!(--"set-times" <WHICH>)
+                    .help("Which times of the copied files are set to the capture time: 'both', 'mtime', 'atime' \
+                        or 'none'. The others are kept from the source file [default: both]"))
+                .arg(arg!(--"preserve-attributes")
+                    .help("Copies the permissions, extended attributes and ACLs of the source files"))
+                .arg(arg!(--"keep-original-mtime")
+                    .help("Keeps the modification time of each source file in the extended attribute \
+                        user.phototools.original_mtime of its copy"))
+                .after_help("Defaults for these options are read from phototools/config.toml in the user's \
+                    configuration directory and from .phototools.toml in the destination directory, \
+                    see 'phototools config show'. Options on the command line override them.")
                 )
+        .subcommand(
+            Command::new("config")
+                .about("Shows the configuration files and settings")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("show")
+                        .about("Prints the effective copy settings and where each one comes from")
+                        .arg(arg!(--"dest-dir" <PATH>)
+                            .short('d')
+                            .help("Also reads the configuration in this destination directory")
+                            .value_parser(value_parser!(PathBuf)))))
 }
 
 fn main() {
@@ -89,6 +189,15 @@ fn main() {
 
             copy(cfg);
         }
+        Some(("config", sub_matches)) => {
+            if let Some(("show", show_matches)) = sub_matches.subcommand() {
+                let dest_dir = show_matches.get_one::<PathBuf>("dest-dir");
+                show_config(dest_dir.map(|d| d.as_path())).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                });
+            }
+        }
         _ => unreachable!()
     }
 }
@@ -98,29 +207,261 @@ struct CopyConfig {
     from_dir: String,
     to_dir: String,
     min_size: u64,
-    shell_cp: bool
+    link_mode: LinkMode,
+    dry_run: bool,
+    move_files: bool,
+    use_index: bool,
+    fail_fast: bool,
+    jobs: usize,
+    timezone: TimezoneMode,
+    layout: Template,
+    rename: Option<Template>,
+    manifest: Option<PathBuf>,
+    filename_dates: FilenameDates,
+    filter: PathFilter,
+    date_range: DateRange,
+    set_times: SetTimes,
+    preserve_attributes: bool,
+    keep_original_mtime: bool
 }
 
 impl CopyConfig {
-    fn from(copy_matches: &ArgMatches) -> Result<CopyConfig, GenError> {
+    fn from(copy_matches: &ArgMatches) -> Result<CopyConfig, Error> {
         let src_dir = copy_matches.get_one::<PathBuf>("source-dir").unwrap();
-        let dst_dir = copy_matches.get_one::<PathBuf>("dest-dir").unwrap();        
-        let min_size = copy_matches.get_one::<u32>("min-size").unwrap();
-        let shell_cp = copy_matches.get_flag("cp-copy");
+        let dst_dir = copy_matches.get_one::<PathBuf>("dest-dir").unwrap();
+        let dry_run = copy_matches.get_flag("dry-run");
+        if copy_matches.get_flag("cp-copy") {
+            warn!("--cp-copy is deprecated and has no effect, use --link-mode to choose how files are made");
+        }
+
+        let mut settings = copy_settings(Some(dst_dir))?;
+        settings.merge(command_line_settings(copy_matches));
+        // The defaults are always set
+        let min_size = settings.get_int("min_size")?.unwrap_or_default();
+        let link_mode = LinkMode::parse(settings.get_str("link_mode")?.unwrap_or_default())?;
+        let move_files = match settings.get_str("mode")?.unwrap_or_default() {
+            "copy" => false,
+            "move" => true,
+            m => return Err(invalid_setting(&settings, "mode", &format!("unknown mode '{}', use copy or move", m)))
+        };
+        let use_index = settings.get_bool("index")?.unwrap_or_default();
+        let fail_fast = settings.get_bool("fail_fast")?.unwrap_or_default();
+        let jobs = settings.get_int("jobs")?.unwrap_or_default();
+        if min_size < 0 || jobs < 1 {
+            let key = if jobs < 1 { "jobs" } else { "min_size" };
+            return Err(invalid_setting(&settings, key, "must be a positive number"));
+        }
+        let timezone = TimezoneMode::parse(settings.get_str("timezone")?.unwrap_or_default())?;
+        let layout = Template::parse_layout(settings.get_str("layout")?.unwrap_or_default())?;
+        let rename = match settings.get_str("rename")? {
+            Some(r) => Some(Template::parse_file_name(r)?),
+            None => None
+        };
+        let manifest = settings.get_str("manifest")?.map(PathBuf::from);
+        let date_patterns = settings.get_strings("date_patterns")?.iter()
+            .map(|p| FilenamePattern::custom(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        let filter = PathFilter::new(&settings.get_strings("include")?, &settings.get_strings("exclude")?,
+            settings.get_bool("include_hidden")?.unwrap_or_default())?;
+        let date_range = DateRange::parse(settings.get_str("since")?, settings.get_str("until")?)?;
+        let set_times = SetTimes::parse(settings.get_str("set_times")?.unwrap_or_default())?;
 
         Ok(CopyConfig {
             from_dir: src_dir.to_string_lossy().into(),
             to_dir: dst_dir.to_string_lossy().into(),
-            min_size: *min_size as u64,
-            shell_cp
+            min_size: min_size as u64,
+            link_mode,
+            dry_run,
+            move_files,
+            use_index,
+            fail_fast,
+            jobs: jobs as usize,
+            timezone,
+            layout,
+            rename,
+            manifest,
+            filename_dates: FilenameDates::with_custom(date_patterns),
+            filter,
+            date_range,
+            set_times,
+            preserve_attributes: settings.get_bool("preserve_attributes")?.unwrap_or_default(),
+            keep_original_mtime: settings.get_bool("keep_original_mtime")?.unwrap_or_default()
         })
     }
 }
 
+/// The defaults, overridden by the configuration files: the user's, then the destination's.
+fn copy_settings(dest_dir: Option<&Path>) -> Result<Config, Error> {
+    const SOURCE: &str = "default";
+    let mut settings = Config::default();
+    settings.set("min_size", Value::Integer(DEFAULT_FILESIZE_MIN), SOURCE);
+    settings.set("link_mode", Value::String("copy".into()), SOURCE);
+    settings.set("mode", Value::String("copy".into()), SOURCE);
+    settings.set("layout", Value::String(DEFAULT_LAYOUT.into()), SOURCE);
+    settings.set("timezone", Value::String("recorded".into()), SOURCE);
+    settings.set("index", Value::Boolean(true), SOURCE);
+    settings.set("jobs", Value::Integer(1), SOURCE);
+    settings.set("fail_fast", Value::Boolean(false), SOURCE);
+    settings.set("include_hidden", Value::Boolean(false), SOURCE);
+    settings.set("set_times", Value::String("both".into()), SOURCE);
+    settings.set("preserve_attributes", Value::Boolean(false), SOURCE);
+    settings.set("keep_original_mtime", Value::Boolean(false), SOURCE);
+
+    let files = Config::load(dest_dir)?;
+    settings.merge(files);
+    Ok(settings)
+}
+
+/// The settings given as options, which override the configuration files.
+fn command_line_settings(copy_matches: &ArgMatches) -> Config {
+    const SOURCE: &str = "command line";
+    let mut settings = Config::default();
+    let set_string = |key: &str, id: &str, settings: &mut Config| {
+        if let Some(v) = copy_matches.get_one::<String>(id) {
+            settings.set(key, Value::String(v.clone()), SOURCE);
+        }
+    };
+    set_string("link_mode", "link-mode", &mut settings);
+    set_string("layout", "layout", &mut settings);
+    set_string("rename", "rename", &mut settings);
+    set_string("timezone", "timezone", &mut settings);
+    set_string("since", "since", &mut settings);
+    set_string("until", "until", &mut settings);
+    set_string("set_times", "set-times", &mut settings);
+    if let Some(min_size) = copy_matches.get_one::<u32>("min-size") {
+        settings.set("min_size", Value::Integer(*min_size as i64), SOURCE);
+    }
+    if let Some(jobs) = copy_matches.get_one::<u32>("jobs") {
+        settings.set("jobs", Value::Integer(*jobs as i64), SOURCE);
+    }
+    if let Some(manifest) = copy_matches.get_one::<PathBuf>("manifest") {
+        settings.set("manifest", Value::String(manifest.to_string_lossy().into()), SOURCE);
+    }
+    let set_strings = |key: &str, id: &str, settings: &mut Config| {
+        if let Some(values) = copy_matches.get_many::<String>(id) {
+            settings.set(key, Value::Array(values.map(|v| Value::String(v.clone())).collect()), SOURCE);
+        }
+    };
+    set_strings("date_patterns", "date-pattern", &mut settings);
+    set_strings("include", "include", &mut settings);
+    set_strings("exclude", "exclude", &mut settings);
+    if copy_matches.get_flag("move") {
+        settings.set("mode", Value::String("move".into()), SOURCE);
+    }
+    if copy_matches.get_flag("copy") {
+        settings.set("mode", Value::String("copy".into()), SOURCE);
+    }
+    if copy_matches.get_flag("no-index") {
+        settings.set("index", Value::Boolean(false), SOURCE);
+    }
+    if copy_matches.get_flag("fail-fast") {
+        settings.set("fail_fast", Value::Boolean(true), SOURCE);
+    }
+    if copy_matches.get_flag("include-hidden") {
+        settings.set("include_hidden", Value::Boolean(true), SOURCE);
+    }
+    if copy_matches.get_flag("preserve-attributes") {
+        settings.set("preserve_attributes", Value::Boolean(true), SOURCE);
+    }
+    if copy_matches.get_flag("keep-original-mtime") {
+        settings.set("keep_original_mtime", Value::Boolean(true), SOURCE);
+    }
+    settings
+}
+
+fn invalid_setting(settings: &Config, key: &str, msg: &str) -> Error {
+    Error::InvalidInput(format!("{}: '{}' {}", settings.source(key).unwrap_or_default(), key, msg))
+}
+
+fn show_config(dest_dir: Option<&Path>) -> Result<(), Error> {
+    let files = Config::user_config_path().into_iter().chain(dest_dir.map(|d| d.join(DESTINATION_CONFIG)));
+    for f in files {
+        let state = if f.exists() { "" } else { " (not found)" };
+        println!("# Configuration file {}{}", f.to_string_lossy(), state);
+    }
+
+    let settings = copy_settings(dest_dir)?;
+    for key in COPY_SETTINGS {
+        match settings.get(key) {
+            Some(value) => println!("{} = {}  # {}", key, value, settings.source(key).unwrap_or_default()),
+            None => println!("# {} is not set", key)
+        }
+    }
+    Ok(())
+}
+
 fn copy(config: CopyConfig) {
     debug!("Source dir: {}", config.from_dir);
     debug!("Target dir: {}", config.to_dir);
 
-    Copier::new(config.min_size, config.shell_cp)
-        .copy(&config.from_dir, &config.to_dir).unwrap();
+    let res = Copier::new(config.min_size)
+        .with_link_mode(config.link_mode)
+        .with_dry_run(config.dry_run)
+        .with_move(config.move_files)
+        .with_index(config.use_index)
+        .with_fail_fast(config.fail_fast)
+        .with_jobs(config.jobs)
+        .with_timezone(config.timezone)
+        .with_layout(config.layout)
+        .with_rename(config.rename)
+        .with_manifest(config.manifest)
+        .with_filename_dates(config.filename_dates)
+        .with_filter(config.filter)
+        .with_date_range(config.date_range)
+        .with_set_times(config.set_times)
+        .with_preserve_attributes(config.preserve_attributes)
+        .with_keep_original_mtime(config.keep_original_mtime)
+        .copy(&config.from_dir, &config.to_dir);
+    let stats = res.unwrap_or_else(|err| {
+        eprintln!("Copy failed: {}", err);
+        process::exit(1);
+    });
+    for planned in &stats.planned {
+        print_planned(planned);
+    }
+    print_summary(&stats, config.dry_run);
+    if stats.errors > 0 {
+        process::exit(1);
+    }
+}
+
+fn print_planned(planned: &PlannedCopy) {
+    let origin = if planned.date_inferred { "inferred from" } else { "from" };
+    let mut notes = vec![format!("date {} {} {}", planned.local_time.format("%Y-%m-%d %H:%M:%S"), origin,
+        planned.date_source.name())];
+    if let Some(suffix) = planned.suffix {
+        notes.push(format!("suffix _{:03}", suffix));
+    }
+    if planned.write_exif {
+        notes.push(if planned.has_exif { "would update exif" } else { "would add exif" }.to_string());
+    }
+    match planned.link_mode {
+        LinkMode::Copy => {},
+        LinkMode::Hard => notes.push("hard link".to_string()),
+        LinkMode::Symbolic => notes.push("symbolic link".to_string()),
+        LinkMode::Reflink => notes.push("reflink".to_string())
+    }
+    if planned.remove_source {
+        notes.push("would remove source".to_string());
+    }
+    println!("{} -> {} ({})", planned.source.to_string_lossy(), planned.target.to_string_lossy(), notes.join(", "));
+}
+
+fn print_summary(stats: &CopyStats, dry_run: bool) {
+    println!();
+    if dry_run {
+        println!("Summary (dry run, nothing was changed)");
+    } else {
+        println!("Summary");
+    }
+    for (name, count) in stats.rows() {
+        println!("  {:<32}{:>8}", name, count);
+    }
+    if !stats.failures.is_empty() {
+        println!();
+        println!("Failed");
+        for (path, err) in &stats.failures {
+            println!("  {}: {}", path.to_string_lossy(), err);
+        }
+    }
 }
@@ -0,0 +1,351 @@
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const QUICKTIME_CREATIONDATE_KEY: &str = "com.apple.quicktime.creationdate";
+const MAKE_KEYS: [&str; 2] = ["com.apple.quicktime.make", "com.android.manufacturer"];
+const MODEL_KEYS: [&str; 2] = ["com.apple.quicktime.model", "com.android.model"];
+/// The top level boxes that older QuickTime files, which have no `ftyp` box, start with.
+const QUICKTIME_FIRST_BOXES: [&[u8; 4]; 6] = [b"moov", b"mdat", b"wide", b"free", b"skip", b"pnot"];
+/// The user type of the `moov/uuid` box in which Canon CR3 files keep their metadata.
+const CANON_METADATA_UUID: [u8; 16] = [0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0,
+    0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48];
+
+/// The date related metadata found in an ISO base media file (MP4, MOV, M4V).
+#[derive(Debug, Default, PartialEq)]
+pub struct Mp4Metadata {
+    /// The `mvhd` creation time, which is in UTC.
+    pub creation_time: Option<NaiveDateTime>,
+    /// The `©day` user data, as found in the file.
+    pub day: Option<String>,
+    /// The `com.apple.quicktime.creationdate` value from `moov/meta`, as found in the file.
+    pub quicktime_creation_date: Option<String>,
+    /// The make of the recording device from `moov/meta`.
+    pub make: Option<String>,
+    /// The model of the recording device from `moov/meta`.
+    pub model: Option<String>
+}
+
+#[derive(Debug)]
+struct BoxHeader {
+    box_type: [u8; 4],
+    // Position of the box content, after the header
+    data_start: u64,
+    end: u64
+}
+
+impl BoxHeader {
+    fn data_len(&self) -> u64 {
+        self.end - self.data_start
+    }
+}
+
+pub fn read_metadata_from_file<P: AsRef<Path>>(p: P) -> io::Result<Mp4Metadata> {
+    let f = File::open(p)?;
+    read_metadata(&mut BufReader::new(f))
+}
+
+pub fn read_metadata<R: Read + Seek>(r: &mut R) -> io::Result<Mp4Metadata> {
+    let len = r.seek(SeekFrom::End(0))?;
+    let mut md = Mp4Metadata::default();
+
+    let top = read_boxes(r, 0, len)?;
+    let old_quicktime = top.first().is_some_and(|b| QUICKTIME_FIRST_BOXES.contains(&&b.box_type))
+        && top.iter().any(|b| &b.box_type == b"moov");
+    if top.first().map(|b| &b.box_type) != Some(b"ftyp") && !old_quicktime {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an ISO base media file"));
+    }
+
+    if let Some(moov) = top.iter().find(|b| &b.box_type == b"moov") {
+        for child in read_boxes(r, moov.data_start, moov.end)? {
+            match &child.box_type {
+                b"mvhd" => md.creation_time = read_mvhd_creation_time(r, &child)?,
+                b"udta" => {
+                    if let Some(day) = read_udta_day(r, &child)? {
+                        md.day = Some(day);
+                    }
+                },
+                b"meta" => {
+                    for (k, v) in read_meta_keys(r, &child)? {
+                        if k == QUICKTIME_CREATIONDATE_KEY {
+                            md.quicktime_creation_date = Some(v);
+                        } else if MAKE_KEYS.contains(&k.as_str()) {
+                            md.make = Some(v);
+                        } else if MODEL_KEYS.contains(&k.as_str()) {
+                            md.model = Some(v);
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+    Ok(md)
+}
+
+/// Reads the `CMT1` (IFD0), `CMT2` (Exif IFD), `CMT3` (maker notes) and `CMT4` (GPS IFD) boxes
+/// of a Canon CR3 file. Each holds a complete TIFF structure.
+pub fn read_cr3_metadata<R: Read + Seek>(r: &mut R) -> io::Result<Vec<([u8; 4], Vec<u8>)>> {
+    let len = r.seek(SeekFrom::End(0))?;
+    let top = read_boxes(r, 0, len)?;
+    if top.first().map(|b| &b.box_type) != Some(b"ftyp") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an ISO base media file"));
+    }
+
+    let mut result = Vec::new();
+    if let Some(moov) = top.iter().find(|b| &b.box_type == b"moov") {
+        for child in read_boxes(r, moov.data_start, moov.end)? {
+            if &child.box_type != b"uuid" || child.data_len() < 16 {
+                continue;
+            }
+            r.seek(SeekFrom::Start(child.data_start))?;
+            let mut uuid = [0; 16];
+            r.read_exact(&mut uuid)?;
+            if uuid != CANON_METADATA_UUID {
+                continue;
+            }
+            for item in read_boxes(r, child.data_start + 16, child.end)? {
+                if item.box_type.starts_with(b"CMT") {
+                    r.seek(SeekFrom::Start(item.data_start))?;
+                    let mut data = vec![0; item.data_len() as usize];
+                    r.read_exact(&mut data)?;
+                    result.push((item.box_type, data));
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn read_boxes<R: Read + Seek>(r: &mut R, start: u64, end: u64) -> io::Result<Vec<BoxHeader>> {
+    let mut boxes = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        r.seek(SeekFrom::Start(pos))?;
+        let size = read_u32(r)? as u64;
+        let mut box_type = [0; 4];
+        r.read_exact(&mut box_type)?;
+
+        let (header_len, size) = match size {
+            0 => (8, end - pos),
+            1 => (16, read_u64(r)?),
+            s => (8, s)
+        };
+        if size < header_len || size > end - pos {
+            // Truncated or corrupt box, don't look any further
+            break;
+        }
+
+        boxes.push(BoxHeader { box_type, data_start: pos + header_len, end: pos + size });
+        pos += size;
+    }
+    Ok(boxes)
+}
+
+fn read_mvhd_creation_time<R: Read + Seek>(r: &mut R, mvhd: &BoxHeader) -> io::Result<Option<NaiveDateTime>> {
+    r.seek(SeekFrom::Start(mvhd.data_start))?;
+    let version = read_u32(r)? >> 24;
+    let secs = if version == 1 { read_u64(r)? } else { read_u32(r)? as u64 };
+
+    // Zero means the time was never set
+    if secs == 0 || secs > i64::MAX as u64 {
+        return Ok(None);
+    }
+    let epoch = NaiveDate::from_ymd_opt(1904, 1, 1).and_then(|d| d.and_hms_opt(0, 0, 0));
+    Ok(epoch.and_then(|e| e.checked_add_signed(Duration::seconds(secs as i64))))
+}
+
+fn read_udta_day<R: Read + Seek>(r: &mut R, udta: &BoxHeader) -> io::Result<Option<String>> {
+    for child in read_boxes(r, udta.data_start, udta.end)? {
+        match &child.box_type {
+            b"\xa9day" => {
+                // QuickTime user data text: 16 bit length, 16 bit language code, text
+                r.seek(SeekFrom::Start(child.data_start))?;
+                let len = (read_u32(r)? >> 16) as u64;
+                if len + 4 <= child.data_len() {
+                    return Ok(Some(read_string(r, len)?));
+                }
+            },
+            b"meta" => {
+                // iTunes style metadata: udta/meta/ilst/©day/data
+                let start = meta_children_start(r, &child)?;
+                for item in read_boxes(r, start, child.end)? {
+                    if &item.box_type == b"ilst" {
+                        for entry in read_boxes(r, item.data_start, item.end)? {
+                            if &entry.box_type == b"\xa9day" {
+                                return read_data_value(r, &entry);
+                            }
+                        }
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the QuickTime `keys` and `ilst` boxes of a `meta` box into key/value pairs,
+/// only text values are returned.
+fn read_meta_keys<R: Read + Seek>(r: &mut R, meta: &BoxHeader) -> io::Result<Vec<(String, String)>> {
+    let start = meta_children_start(r, meta)?;
+    let children = read_boxes(r, start, meta.end)?;
+
+    let mut keys = Vec::new();
+    if let Some(keys_box) = children.iter().find(|b| &b.box_type == b"keys") {
+        r.seek(SeekFrom::Start(keys_box.data_start + 4))?;
+        let count = read_u32(r)?;
+        let mut pos = keys_box.data_start + 8;
+        for _ in 0..count {
+            r.seek(SeekFrom::Start(pos))?;
+            let size = read_u32(r)? as u64;
+            if size < 8 || pos + size > keys_box.end {
+                break;
+            }
+            r.seek(SeekFrom::Current(4))?; // key namespace, e.g. 'mdta'
+            keys.push(read_string(r, size - 8)?);
+            pos += size;
+        }
+    }
+
+    let mut result = Vec::new();
+    if let Some(ilst) = children.iter().find(|b| &b.box_type == b"ilst") {
+        for item in read_boxes(r, ilst.data_start, ilst.end)? {
+            let idx = u32::from_be_bytes(item.box_type) as usize;
+            if idx == 0 || idx > keys.len() {
+                continue;
+            }
+            if let Some(value) = read_data_value(r, &item)? {
+                result.push((keys[idx - 1].clone(), value));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// The `meta` box is a full box in MP4 files but not in QuickTime files. Returns where its children start.
+fn meta_children_start<R: Read + Seek>(r: &mut R, meta: &BoxHeader) -> io::Result<u64> {
+    if meta.data_len() < 8 {
+        return Ok(meta.data_start);
+    }
+    r.seek(SeekFrom::Start(meta.data_start + 4))?;
+    let mut next_type = [0; 4];
+    r.read_exact(&mut next_type)?;
+    if &next_type == b"hdlr" || &next_type == b"keys" {
+        Ok(meta.data_start)
+    } else {
+        Ok(meta.data_start + 4)
+    }
+}
+
+fn read_data_value<R: Read + Seek>(r: &mut R, item: &BoxHeader) -> io::Result<Option<String>> {
+    for data in read_boxes(r, item.data_start, item.end)? {
+        if &data.box_type == b"data" && data.data_len() >= 8 {
+            r.seek(SeekFrom::Start(data.data_start))?;
+            let data_type = read_u32(r)? & 0x00ff_ffff;
+            if data_type == 1 {
+                // UTF-8 text, after the 4 byte locale
+                r.seek(SeekFrom::Current(4))?;
+                return Ok(Some(read_string(r, data.data_len() - 8)?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn read_string<R: Read>(r: &mut R, len: u64) -> io::Result<String> {
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).trim_end_matches('\0').to_string())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+    use std::io::Cursor;
+
+    fn mp4_box(box_type: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut b = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(box_type);
+        b.extend_from_slice(content);
+        b
+    }
+
+    #[test]
+    fn test_mvhd_creation_time() {
+        let md = read_metadata_from_file(testtools::get_base_dir() + "src/test/creation-time.mp4").unwrap();
+        assert_eq!("2019-05-01 17:40:16", md.creation_time.unwrap().format("%Y-%m-%d %T").to_string());
+        assert_eq!(None, md.quicktime_creation_date);
+    }
+
+    #[test]
+    fn test_quicktime_creation_date() {
+        let md = read_metadata_from_file(testtools::get_base_dir() + "src/test2/FROM_IPHONE.MOV").unwrap();
+        assert!(md.quicktime_creation_date.unwrap().starts_with("2018-06-02T"));
+        assert!(md.creation_time.is_some());
+        assert_eq!(Some("Apple".to_string()), md.make);
+        assert!(md.model.unwrap().starts_with("iPhone"));
+    }
+
+    #[test]
+    fn test_no_metadata() {
+        let md = read_metadata_from_file(testtools::get_base_dir() + "src/test/NO_METADATA.M4V").unwrap();
+        assert_eq!(Mp4Metadata::default(), md);
+    }
+
+    #[test]
+    fn test_udta_day() {
+        // 16 bit text length and language code
+        let mut day = vec![0, 19, 0x55, 0xc4];
+        day.extend_from_slice(b"2020-02-29T10:00:00");
+        let udta = mp4_box(b"udta", &mp4_box(b"\xa9day", &day));
+        let mut file = mp4_box(b"ftyp", b"mp42\0\0\0\0");
+        file.extend(mp4_box(b"moov", &udta));
+
+        let md = read_metadata(&mut Cursor::new(file)).unwrap();
+        assert_eq!(Some("2020-02-29T10:00:00".to_string()), md.day);
+    }
+
+    #[test]
+    fn test_quicktime_without_ftyp() {
+        // Version and flags, then the creation time: 2019-05-01 17:40:16
+        let mut mvhd = vec![0; 4];
+        mvhd.extend_from_slice(&3639577216u32.to_be_bytes());
+        let moov = mp4_box(b"moov", &mp4_box(b"mvhd", &mvhd));
+
+        let mut file = mp4_box(b"wide", b"");
+        file.extend(mp4_box(b"mdat", b"data"));
+        file.extend(&moov);
+        let md = read_metadata(&mut Cursor::new(file)).unwrap();
+        assert_eq!("2019-05-01 17:40:16", md.creation_time.unwrap().format("%Y-%m-%d %T").to_string());
+
+        let mut file = moov.clone();
+        file.extend(mp4_box(b"mdat", b"data"));
+        assert!(read_metadata(&mut Cursor::new(file)).unwrap().creation_time.is_some());
+
+        // Without a movie box it isn't recognised
+        let mut file = mp4_box(b"free", b"");
+        file.extend(mp4_box(b"mdat", b"data"));
+        assert!(read_metadata(&mut Cursor::new(file)).is_err());
+    }
+
+    #[test]
+    fn test_not_bmff() {
+        let res = read_metadata_from_file(testtools::get_base_dir() + "src/test/gps-date.jpg");
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use crate::error::{Error, Result};
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the configuration file in the root of a destination directory.
+pub const DESTINATION_CONFIG: &str = ".phototools.toml";
+
+/// A configuration value. Only the TOML types that phototools settings use are supported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>)
+}
+
+impl Value {
+    fn to_toml(&self) -> toml::Value {
+        match self {
+            Value::String(s) => toml::Value::String(s.clone()),
+            Value::Integer(i) => toml::Value::Integer(*i),
+            Value::Boolean(b) => toml::Value::Boolean(*b),
+            Value::Array(values) => toml::Value::Array(values.iter().map(Value::to_toml).collect())
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Formats the value as TOML.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_toml())
+    }
+}
+
+/// The settings a configuration file can have, the same as the options of the copy command.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    min_size: Option<i64>,
+    link_mode: Option<String>,
+    mode: Option<String>,
+    layout: Option<String>,
+    rename: Option<String>,
+    timezone: Option<String>,
+    index: Option<bool>,
+    jobs: Option<i64>,
+    fail_fast: Option<bool>,
+    manifest: Option<String>,
+    date_patterns: Option<Vec<String>>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    include_hidden: Option<bool>,
+    since: Option<String>,
+    until: Option<String>,
+    set_times: Option<String>,
+    preserve_attributes: Option<bool>,
+    keep_original_mtime: Option<bool>
+}
+
+impl ConfigFile {
+    // The settings that are in the file
+    fn into_values(self) -> Vec<(&'static str, Value)> {
+        let strings = |v: Vec<String>| Value::Array(v.into_iter().map(Value::String).collect());
+        let values = vec![
+            ("min_size", self.min_size.map(Value::Integer)),
+            ("link_mode", self.link_mode.map(Value::String)),
+            ("mode", self.mode.map(Value::String)),
+            ("layout", self.layout.map(Value::String)),
+            ("rename", self.rename.map(Value::String)),
+            ("timezone", self.timezone.map(Value::String)),
+            ("index", self.index.map(Value::Boolean)),
+            ("jobs", self.jobs.map(Value::Integer)),
+            ("fail_fast", self.fail_fast.map(Value::Boolean)),
+            ("manifest", self.manifest.map(Value::String)),
+            ("date_patterns", self.date_patterns.map(strings)),
+            ("include", self.include.map(strings)),
+            ("exclude", self.exclude.map(strings)),
+            ("include_hidden", self.include_hidden.map(Value::Boolean)),
+            ("since", self.since.map(Value::String)),
+            ("until", self.until.map(Value::String)),
+            ("set_times", self.set_times.map(Value::String)),
+            ("preserve_attributes", self.preserve_attributes.map(Value::Boolean)),
+            ("keep_original_mtime", self.keep_original_mtime.map(Value::Boolean))
+        ];
+        values.into_iter().filter_map(|(k, v)| Some((k, v?))).collect()
+    }
+}
+
+/// Settings read from configuration files, each with where it came from. Later layers override
+/// earlier ones, see `merge`.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    entries: BTreeMap<String, (Value, String)>
+}
+
+impl Config {
+    /// Parses a TOML configuration file. Unknown settings and values of the wrong type are
+    /// errors. The source, e.g. the file name, is used in error messages and by `source`.
+    pub fn parse(text: &str, source: &str) -> Result<Config> {
+        let file: ConfigFile = toml::from_str(text)
+            .map_err(|e| Error::InvalidInput(format!("{}: {}", source, e.to_string().trim_end())))?;
+        let mut config = Config::default();
+        for (key, value) in file.into_values() {
+            config.set(key, value, source);
+        }
+        Ok(config)
+    }
+
+    /// Reads a configuration file, None if it doesn't exist.
+    pub fn read<P: AsRef<Path>>(p: P) -> Result<Option<Config>> {
+        let p = p.as_ref();
+        match fs::read_to_string(p) {
+            Ok(text) => Ok(Some(Config::parse(&text, &p.to_string_lossy())?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::io(p, e))
+        }
+    }
+
+    /// The per-user configuration file: `phototools/config.toml` in `$XDG_CONFIG_HOME`, or else in
+    /// `~/.config` or `%APPDATA%`.
+    pub fn user_config_path() -> Option<PathBuf> {
+        let var = |name: &str| env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        let dir = var("XDG_CONFIG_HOME")
+            .or_else(|| var("HOME").map(|h| h.join(".config")))
+            .or_else(|| var("APPDATA"))?;
+        Some(dir.join("phototools").join("config.toml"))
+    }
+
+    /// The per-user configuration, overridden by the one in the destination directory, if any.
+    pub fn load(destination: Option<&Path>) -> Result<Config> {
+        let mut config = Config::default();
+        let files = Config::user_config_path().into_iter()
+            .chain(destination.map(|d| d.join(DESTINATION_CONFIG)));
+        for f in files {
+            if let Some(c) = Config::read(&f)? {
+                config.merge(c);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Adds the settings of the other configuration, replacing those with the same key.
+    pub fn merge(&mut self, other: Config) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn set(&mut self, key: &str, value: Value, source: &str) {
+        self.entries.insert(key.to_string(), (value, source.to_string()));
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key).map(|(v, _)| v)
+    }
+
+    /// Where the setting came from.
+    pub fn source(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|(_, s)| s.as_str())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|k| k.as_str())
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<Option<&str>> {
+        match self.entries.get(key) {
+            Some((Value::String(s), _)) => Ok(Some(s)),
+            Some(_) => Err(self.type_error(key, "a string")),
+            None => Ok(None)
+        }
+    }
+
+    pub fn get_int(&self, key: &str) -> Result<Option<i64>> {
+        match self.entries.get(key) {
+            Some((Value::Integer(i), _)) => Ok(Some(*i)),
+            Some(_) => Err(self.type_error(key, "an integer")),
+            None => Ok(None)
+        }
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        match self.entries.get(key) {
+            Some((Value::Boolean(b), _)) => Ok(Some(*b)),
+            Some(_) => Err(self.type_error(key, "true or false")),
+            None => Ok(None)
+        }
+    }
+
+    /// An array of strings, an empty one when the key isn't set.
+    pub fn get_strings(&self, key: &str) -> Result<Vec<String>> {
+        match self.entries.get(key) {
+            Some((Value::Array(values), _)) => values.iter()
+                .map(|v| match v {
+                    Value::String(s) => Ok(s.clone()),
+                    _ => Err(self.type_error(key, "an array of strings"))
+                })
+                .collect(),
+            Some(_) => Err(self.type_error(key, "an array of strings")),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn type_error(&self, key: &str, expected: &str) -> Error {
+        let source = self.source(key).unwrap_or_default();
+        Error::InvalidInput(format!("{}: '{}' must be {}", source, key, expected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools::get_target_dir;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse() {
+        let text = "# Defaults for the photo library\n\
+            min_size = 10_000\n\
+            layout = \"{YYYY}/{MM}\"   # by month\n\
+            \n\
+            rename = '{date}_{name}.{ext}'\n\
+            index = false\r\n\
+            date_patterns = [\n  'scan (?P<day>\\d\\d)',  # comment\n  \"x\\ty\\u00e9\",\n]\n";
+        let config = Config::parse(text, "test.toml").unwrap();
+        assert_eq!(vec!["date_patterns", "index", "layout", "min_size", "rename"], config.keys().collect::<Vec<_>>());
+        assert_eq!(Some(10000), config.get_int("min_size").unwrap());
+        assert_eq!(Some("{YYYY}/{MM}"), config.get_str("layout").unwrap());
+        assert_eq!(Some("{date}_{name}.{ext}"), config.get_str("rename").unwrap());
+        assert_eq!(Some(false), config.get_bool("index").unwrap());
+        assert_eq!(vec!["scan (?P<day>\\d\\d)".to_string(), "x\ty\u{e9}".to_string()],
+            config.get_strings("date_patterns").unwrap());
+        assert_eq!(Some("test.toml"), config.source("layout"));
+        assert_eq!(None, config.get_str("timezone").unwrap());
+        assert!(config.get_str("min_size").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| match Config::parse(text, "test.toml") {
+            Err(Error::InvalidInput(msg)) => msg,
+            r => panic!("Expected an error, got {:?}", r)
+        };
+        let expect = |text: &str, line: usize, msg: &str| {
+            let e = error(text);
+            assert!(e.starts_with(&format!("test.toml: TOML parse error at line {},", line)), "{}", e);
+            assert!(e.ends_with(msg), "{}", e);
+        };
+        expect("jobs = 2\nlayout\n", 2, "expected `.`, `=`");
+        expect("layout = \"{YYYY}\n", 1, "invalid basic string");
+        expect("jobs = 2\njobs = 3\n", 2, "duplicate key `jobs` in document root");
+        expect("index = true x", 1, "expected newline, `#`");
+        // Settings are checked when the file is read
+        expect("jobs = 1.5", 1, "invalid type: floating point `1.5`, expected i64");
+        expect("jobs = '2'", 1, "invalid type: string \"2\", expected i64");
+        assert!(error("min_sise = 10").contains("unknown field `min_sise`, expected one of `min_size`"));
+        assert!(error("[copy]\n").contains("unknown field `copy`"));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!("[-3, true]", Value::Array(vec![Value::Integer(-3), Value::Boolean(true)]).to_string());
+        let value = Value::Array(vec![Value::String("a\"b\\c".to_string()), Value::String("d".to_string())]);
+        assert_eq!("['a\"b\\c', \"d\"]", value.to_string());
+        let config = Config::parse(&format!("exclude = {}", value), "test").unwrap();
+        assert_eq!(Some(&value), config.get("exclude"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = PathBuf::from(get_target_dir() + "test_config");
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join(DESTINATION_CONFIG)).unwrap().write_all(b"jobs = 4\n").unwrap();
+
+        let mut config = Config::parse("jobs = 2\nmin_size = 0", "user").unwrap();
+        config.merge(Config::read(dir.join(DESTINATION_CONFIG)).unwrap().unwrap());
+        assert_eq!(Some(4), config.get_int("jobs").unwrap());
+        assert_eq!(Some(0), config.get_int("min_size").unwrap());
+        assert_eq!(vec!["jobs", "min_size"], config.keys().collect::<Vec<_>>());
+        assert!(Config::read(dir.join("missing.toml")).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,24 +1,112 @@
+use crate::date_range::DateRange;
+use crate::error::{Error, Result};
+use crate::filename_dates::FilenameDates;
+use crate::filetools;
+use crate::filter::{Filtered, PathFilter};
+use crate::hash;
 use crate::image::PhotoHandler;
+use crate::index::ImportIndex;
+use crate::last_import::LastImports;
+use crate::link::LinkMode;
+use crate::manifest::{Action, Manifest, ManifestRecord};
+use crate::sniff::{self, FileKind, Media};
+use crate::stats::{CopyStats, PlannedCopy};
 use crate::video::VideoHandler;
-use crate::strings::Strings;
+use crate::template::{Template, TemplateValues};
+use crate::timestamp::{Timestamp, TimezoneMode};
+use crate::xattr::{self, ORIGINAL_MTIME_ATTR};
 
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
 use filetime::{self, FileTime};
-use log::{info, debug};
+use log::{info, debug, error, warn};
+use std::collections::HashMap;
 use std::io;
-use std::fs::{self, DirEntry};
-use std::path::Path;
-use std::process::Command;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// Which times of a copied file are set to the capture time. The others are kept from the source.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SetTimes {
+    #[default]
+    Both,
+    Modified,
+    Accessed,
+    Neither
+}
 
-type GenError = Box<dyn std::error::Error>;
-pub type GenResult<T> = Result<T, GenError>;
+impl SetTimes {
+    /// Parses `both`, `mtime`, `atime` or `none`.
+    pub fn parse(s: &str) -> Result<SetTimes> {
+        match s.to_lowercase().as_str() {
+            "both" => Ok(SetTimes::Both),
+            "mtime" => Ok(SetTimes::Modified),
+            "atime" => Ok(SetTimes::Accessed),
+            "none" => Ok(SetTimes::Neither),
+            _ => Err(Error::InvalidInput(format!("Invalid file times '{}': use both, mtime, atime or none", s)))
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum DateResult {
-    FromMetadata(String),
-    Inferred(String)
+    FromMetadata(Timestamp, DateSource),
+    Inferred(Timestamp, DateSource)
 }
 
-#[derive(Debug, PartialEq)]
+/// Where a timestamp was found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DateSource {
+    /// EXIF GPSDateStamp and GPSTimeStamp
+    Gps,
+    DateTimeOriginal,
+    /// EXIF DateTime
+    DateTime,
+    /// The com.apple.quicktime.creationdate metadata
+    QuickTime,
+    /// The ©day user data
+    UserDataDate,
+    /// The creation_time of the movie header
+    CreationTime,
+    /// The IDIT chunk of an AVI file
+    AviDate,
+    /// The recording time in the AVCHD stream metadata
+    AvchdDate,
+    /// The DateUTC of a Matroska or WebM file
+    MatroskaDate,
+    /// The Creation Time text of a PNG file
+    PngCreationTime,
+    /// A date in the file name, see FilenameDates
+    Filename,
+    FileModified
+}
+
+impl DateSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DateSource::Gps => "gps",
+            DateSource::DateTimeOriginal => "date_time_original",
+            DateSource::DateTime => "date_time",
+            DateSource::QuickTime => "quicktime",
+            DateSource::UserDataDate => "user_data_date",
+            DateSource::CreationTime => "creation_time",
+            DateSource::AviDate => "avi_date",
+            DateSource::AvchdDate => "avchd_date",
+            DateSource::MatroskaDate => "matroska_date",
+            DateSource::PngCreationTime => "png_creation_time",
+            DateSource::Filename => "filename",
+            DateSource::FileModified => "file_modified"
+        }
+    }
+}
+
+/// Files that only describe another file, such as edits and thumbnails. They are copied along with the
+/// photo or video they belong to.
+const SIDECAR_EXTENSIONS: [&str; 3] = ["xmp", "aae", "thm"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum ResType {
     Photo,
     PhotoTSInferred,
@@ -26,167 +114,976 @@ enum ResType {
     VideoTSInferred
 }
 
+impl ResType {
+    fn media_type(&self) -> &'static str {
+        match self {
+            ResType::Photo | ResType::PhotoTSInferred => "photo",
+            ResType::Video | ResType::VideoTSInferred => "video"
+        }
+    }
+}
+
+/// Everything that was decided about a single source file before anything is written.
+#[derive(Debug)]
+struct CopyPlan {
+    source: PathBuf,
+    target: PathBuf,
+    timestamp: Timestamp,
+    // The local date and time used for the target and the EXIF dates
+    local_time: NaiveDateTime,
+    date_source: DateSource,
+    res_type: ResType,
+    has_exif: bool,
+    write_exif: bool,
+    counter: u32,
+    // Content hash, only computed when the import index or a manifest is used
+    hash: Option<String>,
+    // The camera, only known when the last import times are kept or the templates need it
+    device: Option<String>
+}
+
+/// The date and target of the first file of a group, such as the JPEG of a RAW+JPEG pair or the photo of
+/// a Live Photo. The other files of the group follow it, so they end up next to it with the same name.
+#[derive(Clone, Debug)]
+struct Anchor {
+    timestamp: Timestamp,
+    date_source: DateSource,
+    res_type: ResType,
+    day_dir: PathBuf,
+    // The target name without the part that follows the group stem, e.g. `IMG_1234_001` for IMG_1234.JPG
+    base: String
+}
+
+impl Anchor {
+    fn new(timestamp: Timestamp, date_source: DateSource, res_type: ResType, source: &Path, target: &Path) -> Anchor {
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let suffix = Copier::group_suffix(source);
+        let base = if name.len() > suffix.len() && name.to_ascii_lowercase().ends_with(&suffix.to_ascii_lowercase()) {
+            name[..name.len() - suffix.len()].to_string()
+        } else {
+            target.file_stem().unwrap_or_default().to_string_lossy().into_owned()
+        };
+        Anchor {
+            timestamp,
+            date_source,
+            res_type,
+            day_dir: target.parent().unwrap_or(Path::new("")).to_path_buf(),
+            base
+        }
+    }
+
+    // The name for another file of the group, e.g. IMG_1234_001.CR2 or IMG_1234_001.CR2.xmp
+    fn file_name_for(&self, p: &Path, lowercase: bool) -> String {
+        let suffix = Copier::group_suffix(p);
+        if lowercase {
+            self.base.clone() + &suffix.to_lowercase()
+        } else {
+            self.base.clone() + &suffix
+        }
+    }
+}
+
+/// The targets chosen during a copy, mapped to their source, and the hashes of the content being
+/// imported, mapped to their target. They are taken before any file is written and given up once the
+/// copy is done or failed, so that no two files get the same target. In a dry run they are kept, so
+/// that it knows about the files it would have copied.
+#[derive(Default)]
+struct Reservations {
+    targets: HashMap<PathBuf, PathBuf>,
+    hashes: HashMap<String, PathBuf>
+}
+
+enum TargetFile {
+    New(PathBuf, u32),
+    Identical(PathBuf),
+    // Reserved by a copy of the same content that isn't done yet
+    Pending(PathBuf)
+}
+
 pub struct Copier {
     min_size: u64,
-    shell_cp: bool,
-    video_handler: VideoHandler
+    link_mode: LinkMode,
+    set_times: SetTimes,
+    preserve_attributes: bool,
+    keep_original_mtime: bool,
+    dry_run: bool,
+    use_index: bool,
+    move_files: bool,
+    fail_fast: bool,
+    layout: Template,
+    rename: Option<Template>,
+    manifest_path: Option<PathBuf>,
+    video_handler: VideoHandler,
+    filename_dates: FilenameDates,
+    filter: PathFilter,
+    date_range: DateRange,
+    jobs: usize,
+    timezone: TimezoneMode,
+    reserved: Mutex<Reservations>,
+    released: Condvar,
+    index: Mutex<Option<ImportIndex>>,
+    last_imports: Mutex<Option<LastImports>>,
+    manifest: Mutex<Option<Manifest>>,
+    stats: Mutex<CopyStats>
 }
 
 impl Copier {
-    pub fn new(min_size: u64, shell_cp: bool) -> Copier {
+    pub fn new(min_size: u64) -> Copier {
         Copier {
             min_size,
-            shell_cp,
-            video_handler: VideoHandler::new()
+            link_mode: LinkMode::Copy,
+            set_times: SetTimes::Both,
+            preserve_attributes: false,
+            keep_original_mtime: false,
+            dry_run: false,
+            use_index: false,
+            move_files: false,
+            fail_fast: false,
+            layout: Template::default_layout(),
+            rename: None,
+            manifest_path: None,
+            video_handler: VideoHandler::new(),
+            filename_dates: FilenameDates::default(),
+            filter: PathFilter::default(),
+            date_range: DateRange::default(),
+            jobs: 1,
+            timezone: TimezoneMode::Recorded,
+            reserved: Mutex::new(Reservations::default()),
+            released: Condvar::new(),
+            index: Mutex::new(None),
+            last_imports: Mutex::new(None),
+            manifest: Mutex::new(None),
+            stats: Mutex::new(CopyStats::default())
         }
     }
 
-    pub fn copy(&self, from: &str, to: &str) -> GenResult<()> {
+    /// How the files in the destination are made, copies by default. With hard and symbolic links
+    /// no EXIF dates are added and the file times aren't changed, as that would change the source.
+    pub fn with_link_mode(mut self, link_mode: LinkMode) -> Copier {
+        self.link_mode = link_mode;
+        self
+    }
+
+    /// Which file times are set to the capture time, both by default.
+    pub fn with_set_times(mut self, set_times: SetTimes) -> Copier {
+        self.set_times = set_times;
+        self
+    }
+
+    /// When set, the permissions, extended attributes and ACLs of the source files are copied.
+    pub fn with_preserve_attributes(mut self, preserve_attributes: bool) -> Copier {
+        self.preserve_attributes = preserve_attributes;
+        self
+    }
+
+    /// When set, the modification time of each source file is kept in the extended attribute
+    /// `user.phototools.original_mtime` of its copy.
+    pub fn with_keep_original_mtime(mut self, keep_original_mtime: bool) -> Copier {
+        self.keep_original_mtime = keep_original_mtime;
+        self
+    }
+
+    /// When set, the copy plan is printed but nothing is created, copied or modified.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Copier {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// When set, files whose content was imported into the destination before, under any name
+    /// or date, are skipped. See ImportIndex.
+    pub fn with_index(mut self, use_index: bool) -> Copier {
+        self.use_index = use_index;
+        self
+    }
+
+    /// When set, source files are removed after they have been copied and verified, or when
+    /// they turn out to be in the destination already.
+    pub fn with_move(mut self, move_files: bool) -> Copier {
+        self.move_files = move_files;
+        self
+    }
+
+    /// When set, the copy stops at the first file that fails. By default the error is logged and
+    /// counted, and the copy continues with the next file.
+    pub fn with_fail_fast(mut self, fail_fast: bool) -> Copier {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// The number of files processed in parallel, 1 by default.
+    pub fn with_jobs(mut self, jobs: usize) -> Copier {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Which local time decides the folder and file name. See TimezoneMode.
+    pub fn with_timezone(mut self, timezone: TimezoneMode) -> Copier {
+        self.timezone = timezone;
+        self
+    }
+
+    /// The directory layout below the destination root, `{YYYY}/{YYYY}-{MM}-{DD}` by default.
+    pub fn with_layout(mut self, layout: Template) -> Copier {
+        self.layout = layout;
+        self
+    }
+
+    /// How to name the copied files. By default the original file name is kept.
+    pub fn with_rename(mut self, rename: Option<Template>) -> Copier {
+        self.rename = rename;
+        self
+    }
+
+    /// When set, a JSON Lines file is written with one record per source file: what was done
+    /// with it, where it went and why. See ManifestRecord.
+    pub fn with_manifest(mut self, manifest_path: Option<PathBuf>) -> Copier {
+        self.manifest_path = manifest_path;
+        self
+    }
+
+    /// The patterns that find a date in the name of a file without date metadata, the built-in
+    /// ones by default.
+    pub fn with_filename_dates(mut self, filename_dates: FilenameDates) -> Copier {
+        self.filename_dates = filename_dates;
+        self
+    }
+
+    /// Which files and directories of the source tree to copy. By default hidden ones are left out.
+    pub fn with_filter(mut self, filter: PathFilter) -> Copier {
+        self.filter = filter;
+        self
+    }
+
+    /// Only files with a capture time in this range are copied. By default there are no limits.
+    pub fn with_date_range(mut self, date_range: DateRange) -> Copier {
+        self.date_range = date_range;
+        self
+    }
+
+    pub fn copy(&self, from: &str, to: &str) -> Result<CopyStats> {
         let dir = Path::new(from);
         let t_dir = Path::new(to);
+        if self.move_files && self.link_mode == LinkMode::Symbolic {
+            return Err(Error::InvalidInput("Files can't be moved into symbolic links, as they would point \
+                to the removed sources".to_string()));
+        }
 
-        self.visit_dirs(dir, t_dir, &|f, t| self.copy_direntry(f, t))
+        *lock(&self.reserved) = Reservations::default();
+        *lock(&self.stats) = CopyStats::default();
+        *lock(&self.index) = match (self.use_index, self.dry_run) {
+            (false, _) => None,
+            (true, false) => Some(ImportIndex::open(t_dir).map_err(|e| Error::io(t_dir, e))?),
+            (true, true) => Some(ImportIndex::open_read_only(t_dir).map_err(|e| Error::io(t_dir, e))?)
+        };
+        // Kept along with the import index or a date range, so that a later --since last works
+        *lock(&self.last_imports) = match self.use_index || !self.date_range.is_unbounded() {
+            true => Some(LastImports::open(t_dir).map_err(|e| Error::io(t_dir, e))?),
+            false => None
+        };
+        *lock(&self.manifest) = match &self.manifest_path {
+            Some(p) => Some(Manifest::create(p).map_err(|e| Error::io(p, e))?),
+            None => None
+        };
+        let mut files = Vec::new();
+        let res = self.visit_dirs(dir, dir, &mut files)
+            .and_then(|_| self.copy_files(&Copier::group_files(files), t_dir));
+        *lock(&self.manifest) = None;
+        res?;
+        if let (Some(last_imports), false) = (lock(&self.last_imports).as_mut(), self.dry_run) {
+            last_imports.save().map_err(|e| Error::io(t_dir, e))?;
+        }
+        Ok(lock(&self.stats).clone())
     }
 
-    fn visit_dirs(&self, dir: &Path, tgt_dir: &Path, cb: &dyn Fn(&DirEntry, &Path)->GenResult<()>) -> GenResult<()> {
+    // Collects the files in the directory tree, in the order they are found. Files left out by the
+    // filter are counted here, the directories it leaves out aren't looked into.
+    fn visit_dirs(&self, root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
         if dir.is_dir() {
-            for entry in fs::read_dir(dir)? {
-                let entry = entry?;
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => return self.handle_error(dir, Error::io(dir, e))
+            };
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        self.handle_error(dir, Error::io(dir, e))?;
+                        continue;
+                    }
+                };
                 let path = entry.path();
+                let relative = path.strip_prefix(root).unwrap_or(&path);
                 if path.is_dir() {
-                    self.visit_dirs(&path, tgt_dir, cb)?;
+                    match self.filter.check_dir(relative) {
+                        Filtered::Included => self.visit_dirs(root, &path, files)?,
+                        Filtered::Hidden => info!("Skipping hidden directory: {}", path.to_string_lossy()),
+                        Filtered::Excluded => info!("Skipping excluded directory: {}", path.to_string_lossy())
+                    }
                 } else {
-                    cb(&entry, tgt_dir)?;
+                    match self.filter.check_file(relative) {
+                        Filtered::Included => files.push(path),
+                        Filtered::Hidden => self.skip_file(&path, "hidden")?,
+                        Filtered::Excluded => self.skip_file(&path, "excluded")?
+                    }
                 }
             }
         }
         Ok(())
     }
 
-    fn copy_direntry(&self, direntry: &DirEntry, target_dir: &Path) -> GenResult<()> {
-        let p = direntry.path();
+    fn skip_file(&self, p: &Path, reason: &str) -> Result<()> {
+        info!("Skipping {} file: {}", reason, p.to_string_lossy());
+        let mut stats = lock(&self.stats);
+        stats.scanned += 1;
+        if reason == "hidden" {
+            stats.skipped_hidden += 1;
+        } else {
+            stats.skipped_excluded += 1;
+        }
+        drop(stats);
+        self.write_manifest(self.manifest_record(p, Action::Skipped, reason))
+    }
+
+    // Groups the files that share a stem in the same directory, such as RAW+JPEG pairs, Live Photos
+    // and sidecars. The first file of a group decides the date and name of the others, so RAW files
+    // come first, then other photos, videos and finally sidecars.
+    fn group_files(files: Vec<PathBuf>) -> Vec<Vec<PathBuf>> {
+        let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+        let mut by_stem: HashMap<(Option<PathBuf>, String), usize> = HashMap::new();
+        for p in files {
+            let stem = p.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let stem = stem[..stem.len() - Copier::group_suffix(&p).len()].to_lowercase();
+            let key = (p.parent().map(Path::to_path_buf), stem);
+            match by_stem.get(&key) {
+                Some(&i) => groups[i].push(p),
+                None => {
+                    by_stem.insert(key, groups.len());
+                    groups.push(vec![p]);
+                }
+            }
+        }
+        for group in &mut groups {
+            group.sort_by_key(|p| match FileKind::from_path(p) {
+                Some(FileKind::Tiff | FileKind::Orf | FileKind::Rw2 | FileKind::Raf | FileKind::Cr3) => 0,
+                Some(k) if k.media() == Media::Photo => 1,
+                Some(_) => 2,
+                None => 3
+            });
+        }
+        groups
+    }
+
+    // The part of the file name after the stem it shares with its group: the extension, or for a
+    // sidecar such as IMG_1234.CR2.xmp both extensions
+    fn group_suffix(p: &Path) -> String {
+        let name = p.file_name().unwrap_or_default().to_string_lossy();
+        let mut stem = Path::new(name.as_ref()).file_stem().unwrap_or_default();
+        if Copier::is_sidecar(p) && FileKind::from_path(Path::new(stem)).is_some() {
+            stem = Path::new(stem).file_stem().unwrap_or_default();
+        }
+        name[stem.len()..].to_string()
+    }
+
+    fn is_sidecar(p: &Path) -> bool {
+        let ext = p.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        SIDECAR_EXTENSIONS.contains(&ext.as_str())
+    }
+
+    // Copies the groups of files one by one, or with a pool of worker threads. In fail fast mode the
+    // workers stop at the first error, which is then returned.
+    fn copy_files(&self, groups: &[Vec<PathBuf>], target_dir: &Path) -> Result<()> {
+        if self.jobs == 1 {
+            for group in groups {
+                self.copy_group(group, target_dir)?;
+            }
+            return Ok(());
+        }
+
+        let next = AtomicUsize::new(0);
+        let first_error = Mutex::new(None);
+        thread::scope(|scope| {
+            for _ in 0..self.jobs.min(groups.len()) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(group) = groups.get(i) else {
+                        break;
+                    };
+                    if let Err(e) = self.copy_group(group, target_dir) {
+                        next.store(groups.len(), Ordering::SeqCst);
+                        lock(&first_error).get_or_insert(e);
+                        break;
+                    }
+                });
+            }
+        });
+        match first_error.into_inner().unwrap_or_else(PoisonError::into_inner) {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
+    }
+
+    // Counts and logs an error for a file or directory. Only returns it in fail fast mode, so that
+    // otherwise the copy continues with the next file.
+    fn handle_error(&self, p: &Path, e: Error) -> Result<()> {
+        error!("Failed to process {}: {}", p.to_string_lossy(), e);
+        let mut stats = lock(&self.stats);
+        stats.errors += 1;
+        stats.failures.push((p.to_path_buf(), e.to_string()));
+        drop(stats);
+        if self.fail_fast {
+            Err(e)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Copies the files of a group. Until one of them has a date and target, each file is handled
+    // on its own.
+    fn copy_group(&self, group: &[PathBuf], target_dir: &Path) -> Result<()> {
+        let mut anchor = None;
+        for p in group {
+            let found = self.copy_direntry(p, target_dir, anchor.as_ref())?;
+            if anchor.is_none() {
+                anchor = found;
+            }
+        }
+        Ok(())
+    }
 
-        let file_size = self.file_size(&p);
+    fn copy_direntry(&self, p: &Path, target_dir: &Path, anchor: Option<&Anchor>) -> Result<Option<Anchor>> {
+        lock(&self.stats).scanned += 1;
+        let file_size = self.file_size(p);
         debug!("File {:?} size {}", p, file_size);
         if file_size >= self.min_size {
-            self.copy_file(p, target_dir)?;
+            match self.copy_file(p, target_dir, anchor) {
+                Ok(found) => return Ok(found),
+                Err(e) => {
+                    if let Err(me) = self.write_manifest(self.manifest_record(p, Action::Error, &e.to_string())) {
+                        warn!("Unable to write to the manifest: {}", me);
+                    }
+                    self.handle_error(p, e)?;
+                }
+            }
         } else {
             info!("Skipping {:?} as its size {} is less than {}", p, file_size, self.min_size);
+            lock(&self.stats).skipped_too_small += 1;
+            self.write_manifest(self.manifest_record(p, Action::Skipped, "too small"))?;
         }
-        Ok(())
+        Ok(None)
     }
 
-    fn copy_file<P: AsRef<Path>>(&self, p: P, target_dir: &Path) -> GenResult<()> {
-        if p.as_ref().file_name().unwrap().to_str().unwrap().starts_with(".") {
-            info!("Skipping hidden file: {}", p.as_ref().to_string_lossy());
-            return Ok(());
+    // Returns the date and target of the file, for the other files of its group to follow
+    fn copy_file(&self, p: &Path, target_dir: &Path, anchor: Option<&Anchor>) -> Result<Option<Anchor>> {
+        let (plan, found) = self.plan_file(p, target_dir, anchor)?;
+        if let Some(plan) = plan {
+            if self.dry_run {
+                let planned = self.planned_copy(&plan);
+                lock(&self.stats).planned.push(planned);
+                self.record_import(&plan.hash, &plan.target, None)?;
+            } else {
+                // Once the copy is in the index, or failed, other workers can look at it
+                let res = self.execute_plan(&plan).and_then(|_| self.record_copied(&plan));
+                self.release(&plan);
+                res?;
+            }
+            let mut stats = lock(&self.stats);
+            stats.copied += 1;
+            if plan.counter > 0 {
+                stats.renamed += 1;
+            }
+            if plan.write_exif {
+                stats.exif_written += 1;
+            }
+            drop(stats);
+            // A run with other bounds may have left out earlier files, which a later --since last should still take
+            if self.date_range.is_unbounded() || self.date_range.since_last_import() {
+                if let (Some(last_imports), Some(device)) = (lock(&self.last_imports).as_mut(), &plan.device) {
+                    last_imports.update(device, plan.local_time);
+                }
+            }
+
+            let action = if self.dry_run { Action::Planned } else { Action::Copied };
+            let reason = if plan.counter > 0 { "name collision" } else { "new" };
+            let mut record = self.manifest_record(&plan.source, action, reason);
+            record.target = Some(plan.target.clone());
+            record.timestamp = Some(plan.timestamp);
+            record.timestamp_source = Some(plan.date_source);
+            record.hash = plan.hash.clone();
+            self.remove_source(&plan.source)?;
+            self.write_manifest(record)?;
         }
+        Ok(found)
+    }
 
-        let ext = p.as_ref().extension().unwrap().to_str().unwrap();
-        let ext = ext.to_lowercase();
+    // Decides what to do with a file: the plan for copying it, if it needs copying, and the anchor
+    // for the rest of its group, if it has a date and target. With an anchor the file follows it.
+    fn plan_file(&self, p: &Path, target_dir: &Path, anchor: Option<&Anchor>)
+            -> Result<(Option<CopyPlan>, Option<Anchor>)> {
+        let file_name = p.file_name().ok_or_else(|| Error::unsupported(p, "not a file name"))?;
+        let kind = sniff::detect(p).map_err(|e| Error::io(p, e))?;
+        let media = kind.map(|k| k.media());
+        let anchor = anchor.filter(|_| media.is_some() || Copier::is_sidecar(p));
 
         let mut res_type = ResType::Photo;
         let mut has_exif = true;
-        let ts = match ext.as_str() {
-            "jpeg" |
-            "jpg" |
-            "heic" |
-            "dng" => {
+        let (ts, date_source) = match (anchor, media) {
+            (Some(a), _) => {
+                    debug!("{} follows the date of its group", p.to_string_lossy());
+                    res_type = a.res_type;
+                    (a.timestamp, a.date_source)
+                },
+            (None, Some(Media::Photo)) => {
                     // photo
-                    let (r, x) = PhotoHandler::get_date_time(p.as_ref());
+                    let (r, x) = PhotoHandler::get_date_time_using(p, &self.filename_dates)?;
                     has_exif = x;
                     match r {
-                        DateResult::FromMetadata(s) => s,
-                        DateResult::Inferred(s) => { res_type = ResType::PhotoTSInferred; s }
+                        DateResult::FromMetadata(s, src) => (s, src),
+                        DateResult::Inferred(s, src) => { res_type = ResType::PhotoTSInferred; (s, src) }
                     }
                 },
-            "mp4" | "m4v" | "mov" => {
+            (None, Some(Media::Video)) => {
                     // video
-                    let r = self.video_handler.get_date_time(&p)?;
+                    let r = self.video_handler.get_date_time_using(p, &self.filename_dates)?;
                     match r {
-                        DateResult::FromMetadata(s) => { res_type = ResType::Video; s },
-                        DateResult::Inferred(s) => { res_type = ResType::VideoTSInferred; s }
+                        DateResult::FromMetadata(s, src) => { res_type = ResType::Video; (s, src) },
+                        DateResult::Inferred(s, src) => { res_type = ResType::VideoTSInferred; (s, src) }
                     }
             },
-            _ => {
-                info!("Cannot handle {} - skipping.", p.as_ref().to_string_lossy());
-                return Ok(());
+            (None, None) => {
+                info!("Cannot handle {} - skipping.", p.to_string_lossy());
+                lock(&self.stats).skipped_unsupported += 1;
+                self.write_manifest(self.manifest_record(p, Action::Skipped, "unsupported file type"))?;
+                return Ok((None, None));
             }
         };
 
         debug!("Found timestamp: {:?}", ts);
-        let ts_date = Strings::truncate_at_space(ts.clone());
-
-        if let Some(stem) = p.as_ref().file_name() {
-            let stem = stem.to_string_lossy();
-            let src_file: &str = &p.as_ref().to_string_lossy();
-            let target_dir = target_dir.to_string_lossy().into_owned() + "/" + &ts_date[0..4] + "/" + &ts_date;
-            fs::create_dir_all(&target_dir)?;
-            let org_target_file = target_dir + "/" + &stem;
-
-            let mut counter = 1;
-            let mut path = Path::new(&org_target_file);
-            let mut target_file = org_target_file.clone();
-            while path.exists() {
-                if let Ok(md) = path.metadata() {
-                    if md.len() == 0 {
-                        // Delete this empty file
-                        if let Ok(()) = fs::remove_file(path) {
-                            continue;
+        let date_time = ts.local_time(self.timezone);
+
+        let uses_camera = self.layout.uses_camera() || self.rename.as_ref().is_some_and(|t| t.uses_camera());
+        let keeps_last_imports = lock(&self.last_imports).is_some();
+        let (make, model) = if uses_camera || keeps_last_imports {
+            match res_type.media_type() {
+                "video" => self.video_handler.get_camera(p),
+                _ => PhotoHandler::get_camera(p)
+            }
+        } else {
+            (None, None)
+        };
+        let device = keeps_last_imports.then(|| LastImports::device_name(make.as_deref(), model.as_deref()));
+
+        // The files of a group follow the first one, which was in range
+        if anchor.is_none() && !self.date_range.is_unbounded() {
+            let last_import = device.as_ref()
+                .and_then(|d| lock(&self.last_imports).as_ref().and_then(|l| l.get(d)));
+            if !self.date_range.contains(date_time, last_import) {
+                info!("Skipping {} as its date {} is outside the date range", p.to_string_lossy(), date_time);
+                lock(&self.stats).skipped_out_of_range += 1;
+                let mut record = self.manifest_record(p, Action::Skipped, "outside date range");
+                record.timestamp = Some(ts);
+                record.timestamp_source = Some(date_source);
+                self.write_manifest(record)?;
+                return Ok((None, None));
+            }
+        }
+
+        match res_type {
+            ResType::Photo | ResType::Video => lock(&self.stats).dates_from_metadata += 1,
+            ResType::PhotoTSInferred | ResType::VideoTSInferred => lock(&self.stats).dates_inferred += 1
+        }
+
+        let hash = if self.use_index || self.manifest_path.is_some() {
+            Some(hash::hash_file(p).map_err(|e| Error::io(p, e))?)
+        } else {
+            None
+        };
+        let values = TemplateValues {
+            date_time,
+            make: make.as_deref(),
+            model: model.as_deref(),
+            media_type: res_type.media_type(),
+            ext: &p.extension().unwrap_or_default().to_string_lossy(),
+            name: &p.file_stem().unwrap_or_default().to_string_lossy(),
+            seq: 1
+        };
+        let day_dir = match anchor {
+            Some(a) => a.day_dir.clone(),
+            None => target_dir.join(self.layout.render_dir(&values))
+        };
+        let follower_name = anchor.map(|a| a.file_name_for(p, self.rename.is_some()));
+        let name_for = |counter: u32| match (&follower_name, &self.rename) {
+            (Some(name), _) => Copier::numbered_file_name(&day_dir.join(name), counter),
+            (None, Some(t)) if t.uses_seq() => day_dir.join(t.render_file_name(&TemplateValues { seq: counter + 1, ..values })),
+            (None, Some(t)) => Copier::numbered_file_name(&day_dir.join(t.render_file_name(&values)), counter),
+            (None, None) => Copier::numbered_file_name(&day_dir.join(file_name), counter)
+        };
+        let anchor_for = |target: &Path| match anchor {
+            Some(_) => None,
+            None => Some(Anchor::new(ts, date_source, res_type, p, target))
+        };
+
+        // Parallel workers reserve the target and content of a copy until it is done, so that they
+        // never pick the same name or import the same content twice. Files are hashed and compared
+        // without holding the lock, so what was found is checked again when reserving.
+        let (target, counter) = loop {
+            if let Some(stored) = hash.as_ref().and_then(|h| self.find_import(h)) {
+                info!("{} already imported as {}", p.to_string_lossy(), stored.to_string_lossy());
+                lock(&self.stats).skipped_already_imported += 1;
+                self.skip_duplicate(p, &stored, "already imported", ts, date_source, hash)?;
+                return Ok((None, anchor_for(&stored)));
+            }
+            match self.find_target_file(p, &name_for) {
+                TargetFile::Identical(existing) => {
+                    info!("Identical file already exists {}", existing.to_string_lossy());
+                    lock(&self.stats).skipped_identical += 1;
+                    self.record_import(&hash, &existing, None)?;
+                    self.skip_duplicate(p, &existing, "identical file exists", ts, date_source, hash)?;
+                    return Ok((None, anchor_for(&existing)));
+                },
+                // Whether that copy succeeds decides what happens to this file
+                TargetFile::Pending(target) => self.wait_for_release(|r| r.targets.contains_key(&target)),
+                TargetFile::New(target, counter) => if self.reserve(p, &target, &hash) {
+                    break (target, counter);
+                }
+            }
+        };
+
+        let write_exif = res_type == ResType::PhotoTSInferred && !self.link_mode.shares_file()
+            && PhotoHandler::can_write_exif(p);
+        let found = anchor_for(&target);
+        Ok((Some(CopyPlan {
+            source: p.to_path_buf(),
+            target,
+            timestamp: ts,
+            local_time: date_time,
+            date_source,
+            res_type,
+            has_exif,
+            write_exif,
+            counter,
+            hash,
+            device
+        }), found))
+    }
+
+    // Records a file that doesn't need copying, as the content already is at the stored path
+    fn skip_duplicate(&self, p: &Path, stored: &Path, reason: &str, ts: Timestamp, date_source: DateSource,
+            hash: Option<String>) -> Result<()> {
+        let mut record = self.manifest_record(p, Action::Duplicate, reason);
+        record.target = Some(stored.to_path_buf());
+        record.timestamp = Some(ts);
+        record.timestamp_source = Some(date_source);
+        record.hash = hash;
+        self.remove_source(p)?;
+        self.write_manifest(record)
+    }
+
+    // Where content with this hash was imported before, or would be in this dry run. When another
+    // worker is still copying it, its outcome is waited for first.
+    fn find_import(&self, hash: &str) -> Option<PathBuf> {
+        self.wait_for_release(|r| r.hashes.contains_key(hash));
+        if let Some(planned) = lock(&self.reserved).hashes.get(hash) {
+            return Some(planned.clone());
+        }
+
+        // The stored file is checked without holding the lock on the index
+        let (stored, expected) = lock(&self.index).as_ref()?.get(hash)?;
+        let Some(expected) = expected else {
+            return Some(stored);
+        };
+        let still_there = ImportIndex::has_hash(&stored, &expected);
+        if let Some(index) = lock(&self.index).as_mut() {
+            index.checked(hash, &stored, still_there);
+        }
+        still_there.then_some(stored)
+    }
+
+    // Waits until no copy in progress matches. In a dry run nothing is in progress.
+    fn wait_for_release(&self, pending: impl Fn(&Reservations) -> bool) {
+        if self.dry_run {
+            return;
+        }
+        let reserved = lock(&self.reserved);
+        drop(self.released.wait_while(reserved, |r| pending(r)).unwrap_or_else(PoisonError::into_inner));
+    }
+
+    // Reserves the target, and the content when the index is used, unless another worker got there
+    // since they were looked up
+    fn reserve(&self, src: &Path, target: &Path, hash: &Option<String>) -> bool {
+        let mut reserved = lock(&self.reserved);
+        let hash = hash.as_ref().filter(|_| self.use_index);
+        if reserved.targets.contains_key(target) || (!self.dry_run && target.exists()) {
+            return false;
+        }
+        if let Some(h) = hash {
+            if reserved.hashes.contains_key(h) || lock(&self.index).as_ref().is_some_and(|i| i.contains(h)) {
+                return false;
+            }
+            reserved.hashes.insert(h.clone(), target.to_path_buf());
+        }
+        reserved.targets.insert(target.to_path_buf(), src.to_path_buf());
+        true
+    }
+
+    // Gives up the target and hash reserved for a plan, once it was carried out or failed
+    fn release(&self, plan: &CopyPlan) {
+        let mut reserved = lock(&self.reserved);
+        reserved.targets.remove(&plan.target);
+        if let Some(h) = &plan.hash {
+            if reserved.hashes.get(h) == Some(&plan.target) {
+                reserved.hashes.remove(h);
+            }
+        }
+        self.released.notify_all();
+    }
+
+    /// Finds the first free name for the target, trying the names for counter 0, 1, 2 etc. Empty files in
+    /// the way are replaced. If an identical file is found at one of the candidate names nothing needs copying.
+    fn find_target_file(&self, src: &Path, name_for: &dyn Fn(u32) -> PathBuf) -> TargetFile {
+        let mut counter = 0;
+        let mut target_file = name_for(counter);
+        let mut same_size_collisions = Vec::new();
+        loop {
+            let reserved_src = lock(&self.reserved).targets.get(&target_file).cloned();
+            let existing = match &reserved_src {
+                Some(reserved_src) => Some(reserved_src.clone()),
+                None if target_file.exists() => {
+                    if Copier::is_empty_file(&target_file) {
+                        if self.dry_run {
+                            // The empty file would be deleted and replaced
+                            None
+                        } else if let Ok(()) = fs::remove_file(&target_file) {
+                            // Deleted this empty file
+                            None
+                        } else {
+                            Some(target_file.clone())
                         }
+                    } else {
+                        Some(target_file.clone())
+                    }
+                },
+                None => None
+            };
+
+            let existing = match existing {
+                Some(existing) => existing,
+                None => {
+                    for other in same_size_collisions {
+                        info!("{} has the same size as {} but different content, using {}",
+                            src.to_string_lossy(), other, target_file.to_string_lossy());
                     }
+                    return TargetFile::New(target_file, counter);
                 }
+            };
+
+            if Copier::identical_file(src, &existing) {
+                // A reserved target isn't there yet, unless this is a dry run
+                return match reserved_src.is_some() && !self.dry_run {
+                    true => TargetFile::Pending(target_file),
+                    false => TargetFile::Identical(target_file)
+                };
+            }
+            if reserved_src.is_some() && !lock(&self.reserved).targets.contains_key(&target_file) {
+                // Done meanwhile, a moved source may be gone by now, so compare with the copy instead
+                continue;
+            }
+            if Copier::same_size(src, &existing) {
+                same_size_collisions.push(target_file.to_string_lossy().into_owned());
+            }
 
-                if Copier::identical_file(&p.as_ref(), &path) {
-                    info!("Identical file already exists {}", target_file);
-                    return Ok(());
-                }
+            // if target file exists, add _001
+            counter += 1;
+            target_file = name_for(counter);
+        }
+    }
+    // Adds _001, _002 etc. to the file name, counter 0 leaves it as is
+    fn numbered_file_name(org_target_file: &Path, counter: u32) -> PathBuf {
+        if counter == 0 {
+            return org_target_file.to_path_buf();
+        }
+        let base = org_target_file.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match org_target_file.extension() {
+            Some(ext) => format!("{}_{:03}.{}", base, counter, ext.to_string_lossy()),
+            None => format!("{}_{:03}", base, counter)
+        };
+        org_target_file.with_file_name(name)
+    }
 
-                let base;
-                let ext;
-                if let Some(idx) = org_target_file.rfind('.') {
-                    base = &org_target_file[..idx];
-                    ext = &org_target_file[idx..];
-                } else {
-                    base = &org_target_file;
-                    ext = "";
-                }
-                // if target file exists, add _001
-                target_file = format!("{}_{:03}{}", base, counter, ext);
-                path = Path::new(&target_file);
-                counter += 1;
+    // Adds a copied file to the index. Writing the EXIF dates changed the stored file, so then the index
+    // checks it against its own hash.
+    fn record_copied(&self, plan: &CopyPlan) -> Result<()> {
+        let stored_hash = match (&plan.hash, self.use_index && plan.write_exif) {
+            (Some(_), true) => Some(hash::hash_file(&plan.target).map_err(|e| Error::io(&plan.target, e))?),
+            _ => None
+        };
+        self.record_import(&plan.hash, &plan.target, stored_hash.as_deref())
+    }
+
+    // Adds the content to the index. The stored hash is only given when the stored file differs from the source.
+    fn record_import(&self, hash: &Option<String>, stored: &Path, stored_hash: Option<&str>) -> Result<()> {
+        if let (Some(hash), Some(index)) = (hash, lock(&self.index).as_mut()) {
+            index.add(hash, stored, stored_hash.unwrap_or(hash))?;
+        }
+        Ok(())
+    }
+
+    fn manifest_record(&self, p: &Path, action: Action, reason: &str) -> ManifestRecord {
+        let mut record = ManifestRecord::new(p, action);
+        record.reason = Some(reason.to_string());
+        record.size = fs::metadata(p).ok().map(|md| md.len());
+        record
+    }
+
+    fn write_manifest(&self, record: ManifestRecord) -> Result<()> {
+        if let Some(manifest) = lock(&self.manifest).as_mut() {
+            manifest.write(&record)?;
+        }
+        Ok(())
+    }
+
+    fn planned_copy(&self, plan: &CopyPlan) -> PlannedCopy {
+        PlannedCopy {
+            source: plan.source.clone(),
+            target: plan.target.clone(),
+            local_time: plan.local_time,
+            date_source: plan.date_source,
+            date_inferred: matches!(plan.res_type, ResType::PhotoTSInferred | ResType::VideoTSInferred),
+            // A sequence number in the name isn't a suffix
+            suffix: Some(plan.counter).filter(|c| *c > 0 && !self.rename.as_ref().is_some_and(|t| t.uses_seq())),
+            write_exif: plan.write_exif,
+            has_exif: plan.has_exif,
+            link_mode: self.link_mode,
+            remove_source: self.move_files
+        }
+    }
+
+    fn execute_plan(&self, plan: &CopyPlan) -> Result<()> {
+        let src_file: &str = &plan.source.to_string_lossy();
+        let target_file: &str = &plan.target.to_string_lossy();
+        if let Some(day_dir) = plan.target.parent() {
+            fs::create_dir_all(day_dir).map_err(|e| Error::io(day_dir, e))?;
+        }
+
+        let mut add_txt = "";
+        if plan.write_exif {
+            add_txt = ", will update exif."
+        }
+        info!("Copying {} to {}{}", src_file, target_file, add_txt);
+        // Read before copying, which may change the access time
+        let source_md = fs::metadata(&plan.source).map_err(|e| Error::io(&plan.source, e))?;
+
+        // Copy to a temporary file first, so that the target name only exists once it's complete
+        let partial = Copier::partial_file_name(&plan.target);
+        if let Err(e) = self.copy_to_partial(plan, &partial) {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        if plan.target.exists() {
+            // Something else wrote to the destination since the target was chosen
+            let _ = fs::remove_file(&partial);
+            return Err(Error::conflict(&plan.target, "a file with this name was created during the copy"));
+        }
+        fs::rename(&partial, &plan.target).map_err(|e| Error::io(&plan.target, e))?;
+
+        if plan.write_exif {
+            PhotoHandler::set_exif_date_time(&plan.target, &plan.local_time)?;
+        }
+        // After the EXIF dates, which are written to a new file
+        if !self.link_mode.shares_file() {
+            self.copy_attributes(plan, &source_md);
+        }
+
+        let new_dt = plan.timestamp.file_time(self.timezone);
+        let capture_time = FileTime::from_unix_time(new_dt.timestamp(), 0);
+        let accessed = FileTime::from_last_access_time(&source_md);
+        let modified = FileTime::from_last_modification_time(&source_md);
+        let (atime, mtime) = match self.set_times {
+            SetTimes::Both => (capture_time, capture_time),
+            SetTimes::Modified => (accessed, capture_time),
+            SetTimes::Accessed => (capture_time, modified),
+            SetTimes::Neither => (accessed, modified)
+        };
+        match self.link_mode {
+            // The times belong to the source file
+            LinkMode::Hard => {},
+            LinkMode::Symbolic => {
+                debug!("Setting link date and time to: {}", plan.timestamp);
+                filetime::set_symlink_file_times(target_file, atime, mtime).map_err(|e| Error::io(&plan.target, e))?;
+            },
+            LinkMode::Copy | LinkMode::Reflink => {
+                debug!("Setting file date and time to: {}", plan.timestamp);
+                filetime::set_file_times(target_file, atime, mtime).map_err(|e| Error::io(&plan.target, e))?;
             }
+        }
+        Ok(())
+    }
 
-            let mut add_txt = "";
-            if res_type == ResType::PhotoTSInferred {
-                add_txt = ", will update exif."
+    // Only warns when an attribute can't be copied, as destinations such as memory cards
+    // formatted with FAT can't hold them
+    fn copy_attributes(&self, plan: &CopyPlan, source_md: &fs::Metadata) {
+        if self.preserve_attributes {
+            if let Err(e) = fs::set_permissions(&plan.target, source_md.permissions()) {
+                warn!("Unable to set the permissions of {}: {}", plan.target.to_string_lossy(), e);
             }
-            info!("Copying {} to {}{}", src_file, target_file, add_txt);
-
-            if self.shell_cp {
-                Command::new("cp")
-                    .arg(&src_file)
-                    .arg(&target_file)
-                    .output()
-                    .expect("Failed to execute cp.");
-            } else {
-                fs::copy(src_file, &target_file)?;
+            // Also sets the ACLs, which change the permissions
+            if let Err(e) = xattr::copy_all(&plan.source, &plan.target) {
+                warn!("Unable to copy the extended attributes of {} to {}: {}",
+                    plan.source.to_string_lossy(), plan.target.to_string_lossy(), e);
             }
+        }
+        if self.keep_original_mtime {
+            // A source imported before keeps the time it had before that import
+            let original = match xattr::get(&plan.source, ORIGINAL_MTIME_ATTR) {
+                Ok(Some(value)) => Some(value),
+                _ => source_md.modified().ok()
+                    .map(|t| DateTime::<Utc>::from(t).to_rfc3339_opts(SecondsFormat::AutoSi, true).into_bytes())
+            };
+            if let Err(e) = original.map_or(Ok(()), |o| xattr::set(&plan.target, ORIGINAL_MTIME_ATTR, &o)) {
+                warn!("Unable to keep the original modification time of {}: {}", plan.target.to_string_lossy(), e);
+            }
+        }
+    }
 
-            if res_type == ResType::PhotoTSInferred {
-                PhotoHandler::set_exif_date_time(&target_file, &ts, !has_exif); // TODO check if exif was there or not
+    fn copy_to_partial(&self, plan: &CopyPlan, partial: &Path) -> Result<()> {
+        // Left behind by an interrupted copy
+        if fs::symlink_metadata(partial).is_ok() {
+            fs::remove_file(partial).map_err(|e| Error::io(partial, e))?;
+        }
+        self.link_mode.create(&plan.source, partial).map_err(|e| Error::io(&plan.source, e))?;
+
+        if self.move_files && !self.link_mode.shares_file() {
+            // The source is going to be deleted, so make sure the copy is complete and correct
+            let src_hash = match &plan.hash {
+                Some(h) => h.clone(),
+                None => hash::hash_file(&plan.source).map_err(|e| Error::io(&plan.source, e))?
+            };
+            if !Copier::same_size(&plan.source, partial)
+                    || src_hash != hash::hash_file(partial).map_err(|e| Error::io(partial, e))? {
+                return Err(Error::io(partial, io::Error::new(io::ErrorKind::InvalidData,
+                    format!("copy of {} differs from the original", plan.source.to_string_lossy()))));
             }
+        }
+        Ok(())
+    }
 
-            debug!("Setting file date and time to: {}", ts);
-            let new_dt = chrono::NaiveDateTime::parse_from_str(&ts, "%Y-%m-%d %H:%M:%S")?;
-            let unix_ts = FileTime::from_unix_time(new_dt.and_utc().timestamp(), 0);
-            filetime::set_file_times(&target_file, unix_ts, unix_ts)?;
-            Ok(())
-            // TODO can we somehow delete the file if the copy didn't fully succeed?
+    fn partial_file_name(target: &Path) -> PathBuf {
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        target.with_file_name(format!(".{}.phototools-partial", name))
+    }
+
+    /// In move mode, removes a source file once it is known to be safely in the destination.
+    fn remove_source(&self, p: &Path) -> Result<()> {
+        if !self.move_files {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!("Would remove {}", p.to_string_lossy());
         } else {
-            // TODO we should not need the GenError box
-            Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, format!("Problem with file: {:?}", p.as_ref()))))
+            fs::remove_file(p).map_err(|e| Error::io(p, e))?;
+            info!("Removed {}", p.to_string_lossy());
+            lock(&self.stats).sources_removed += 1;
         }
+        Ok(())
     }
 
     fn file_size<P: AsRef<Path>>(&self, p: P) -> u64 {
@@ -197,33 +1094,52 @@ impl Copier {
     }
 
     fn identical_file(p1: &Path, p2: &Path) -> bool {
-        // TODO better file compare, also based on content...
-        if let Ok(md1) = fs::metadata(p1) {
-            if let Ok(md2) = fs::metadata(p2) {
-                return md1.len() == md2.len();
+        match filetools::same_content(p1, p2) {
+            Ok(same) => same,
+            Err(e) => {
+                debug!("Unable to compare {:?} with {:?}: {}", p1, p2, e);
+                false
             }
         }
-        false
+    }
+
+    fn is_empty_file(p: &Path) -> bool {
+        matches!(p.metadata(), Ok(md) if md.len() == 0)
+    }
+
+    fn same_size(p1: &Path, p2: &Path) -> bool {
+        match (fs::metadata(p1), fs::metadata(p2)) {
+            (Ok(md1), Ok(md2)) => md1.len() == md2.len(),
+            _ => false
+        }
     }
 }
 
+// A panic in one worker should not stop the others from using the shared state
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::filetools;
+    use crate::strings::Strings;
+    use crate::sniff::FileKind;
     use crate::testtools::get_target_dir;
     use crate::testtools::assert_files_equal;
     use chrono::DateTime;
     use chrono::offset::Utc;
     use std::fs;
+    use std::process::Command;
     use std::time::{SystemTime, UNIX_EPOCH};
 
     #[test]
 
-    fn test_add_exif() -> io::Result<()> {
+    fn test_add_exif() -> Result<()> {
         let td = get_target_dir();
 
-        let copier = Copier::new(0, false);
+        let copier = Copier::new(0);
         let sd = td.clone() + "../src/test3";
         let tdp1 = td.clone() + "test_photo3a";
         ensure_dir_doesnt_exist(&tdp1);
@@ -240,9 +1156,11 @@ mod tests {
         assert_ne!(md0.len(), md1.len(), "Should have added EXIF metadata to the JPEG");
 
         // Now copy the file again, since it has the EXIF data now, it should not get it again
-        PhotoHandler::set_exif_date_time(&p1, "2001:12:29-07:00:01", false);
+        let dt = NaiveDateTime::parse_from_str("2001-12-29 07:00:01", "%Y-%m-%d %H:%M:%S").unwrap();
+        PhotoHandler::set_exif_date_time(&p1, &dt).unwrap();
         let sd2 = tdp1.clone() + &expected_dir;
         let tdp2 = td.clone() + "test_photo3b";
+        ensure_dir_doesnt_exist(&tdp2);
         copier.copy(&sd2, &tdp2).unwrap();
 
         let p2 = tdp2.clone() + "/2001/2001-12-29/NO_METADATA.JPEG";
@@ -252,14 +1170,14 @@ mod tests {
     }
 
     #[test]
-    fn test_copy() -> io::Result<()> {
+    fn test_copy() -> Result<()> {
         // TODO make sure different tests write to different locations
         // TODO empty target directories before test run
         let td = get_target_dir();
         assert!(td.ends_with("/phototools/target/"));
         println!("Target dir {} ", td);
 
-        let copier = Copier::new(0, false);
+        let copier = Copier::new(0);
         let sd = td.clone() + "../src/test";
         let tdp1 = td.clone() + "test_photo";
         ensure_dir_doesnt_exist(&tdp1);
@@ -289,8 +1207,9 @@ mod tests {
         let file_date = Strings::truncate_at_space(file_time2);
         assert_eq!("2017-07-01", file_date);
 
-        assert_files_equal(sd.clone() + "/subdir/VID-20181129-WA9876.mp4",
-            tdp1.clone() + "/2018/2018-11-29/VID-20181129-WA9876.mp4");
+        // A JPEG despite its extension, which gets the date from its name in EXIF
+        let whatsapp = tdp1.clone() + "/2018/2018-11-29/VID-20181129-WA9876.mp4";
+        assert_eq!(Some(FileKind::Jpeg), sniff::detect(Path::new(&whatsapp))?);
         let file_time3 = filetools::get_time_from_file(tdp1.clone() + "/2018/2018-11-29/VID-20181129-WA9876.mp4")?;
         let file_date3 = Strings::truncate_at_space(file_time3);
         assert_eq!("2018-11-29", file_date3);
@@ -311,12 +1230,13 @@ mod tests {
     }
 
     #[test]
-    fn test_copy_using_cp() -> io::Result<()> {
+    fn test_copy_reflink() -> Result<()> {
         let td = get_target_dir();
         assert!(td.ends_with("/phototools/target/"));
         println!("Target dir {} ", td);
 
-        let copier = Copier::new(0, true);
+        // Copied normally where the filesystem can't share the data
+        let copier = Copier::new(0).with_link_mode(LinkMode::Reflink);
         let sd = td.clone() + "../src/test";
         let tdp1 = td.clone() + "test_photo0";
         ensure_dir_doesnt_exist(&tdp1);
@@ -346,8 +1266,9 @@ mod tests {
         let file_date = Strings::truncate_at_space(file_time2);
         assert_eq!("2017-07-01", file_date);
 
-        assert_files_equal(sd.clone() + "/subdir/VID-20181129-WA9876.mp4",
-            tdp1.clone() + "/2018/2018-11-29/VID-20181129-WA9876.mp4");
+        // A JPEG despite its extension, which gets the date from its name in EXIF
+        let whatsapp = tdp1.clone() + "/2018/2018-11-29/VID-20181129-WA9876.mp4";
+        assert_eq!(Some(FileKind::Jpeg), sniff::detect(Path::new(&whatsapp))?);
         let file_time3 = filetools::get_time_from_file(tdp1.clone() + "/2018/2018-11-29/VID-20181129-WA9876.mp4")?;
         let file_date3 = Strings::truncate_at_space(file_time3);
         assert_eq!("2018-11-29", file_date3);
@@ -356,12 +1277,17 @@ mod tests {
     }
 
     #[test]
-    fn test_min_size() -> io::Result<()> {
-        let copier = Copier::new(100000, false);
+    fn test_min_size() -> Result<()> {
+        let copier = Copier::new(100000);
         let sd = get_target_dir() + "../src/test";
         let td = get_target_dir() + "test_min_size";
         ensure_dir_doesnt_exist(&td);
-        copier.copy(&sd, &td).unwrap();
+        let stats = copier.copy(&sd, &td).unwrap();
+        // The 3 files below and a HEIC file from 2023
+        assert_eq!(4, stats.copied);
+        assert_eq!(4, stats.dates_from_metadata);
+        assert_eq!(1, stats.skipped_hidden);
+        assert_eq!(stats.scanned - 5, stats.skipped_too_small);
 
         let td0 = td + "/2019";
         let td1 = dir_has_file(&td0, "2019-05-01");
@@ -375,10 +1301,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_continue_on_error() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_continue_on_error_src";
+        let target_dir = td.clone() + "test_continue_on_error";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        fs::create_dir_all(&source_dir).unwrap();
+        fs::copy(td.clone() + "../src/test1a/myimg.jpg", source_dir.clone() + "/myimg.jpg").unwrap();
+        fs::copy(td.clone() + "../src/test/creation-time.mp4", source_dir.clone() + "/creation-time.mp4").unwrap();
+        fs::write(source_dir.clone() + "/README", b"no extension").unwrap();
+
+        // A file where the day directory of the photo should be makes copying the photo fail
+        fs::create_dir_all(target_dir.clone() + "/2019").unwrap();
+        fs::write(target_dir.clone() + "/2019/2019-04-27", b"in the way").unwrap();
+
+        let stats = Copier::new(0).with_fail_fast(true).copy(&source_dir, &target_dir);
+        assert!(stats.is_err());
+
+        let stats = Copier::new(0).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.errors);
+        assert_eq!(1, stats.skipped_unsupported);
+        assert_eq!(PathBuf::from(source_dir.clone() + "/myimg.jpg"), stats.failures[0].0);
+        dir_exact(&(target_dir.clone() + "/2019/2019-05-01"), &["creation-time.mp4"]);
+    }
+
     #[test]
     fn test_dont_replace_same_file() {
         let td = get_target_dir();
-        let copier = Copier::new(0, false);
+        let copier = Copier::new(0);
         let source_dir_a = td.clone() + "../src/test1a";
         let target_dir = td.clone() + "test_photo1";
         ensure_dir_doesnt_exist(&target_dir);
@@ -405,7 +1357,7 @@ mod tests {
     #[test]
     fn test_dont_replace_same_file2() {
         let td = get_target_dir();
-        let copier = Copier::new(0, false);
+        let copier = Copier::new(0);
         let source_dir_a = td.clone() + "../src/test1a";
 
         // Copy file from different directory. File has the same name, but different content
@@ -443,12 +1395,646 @@ mod tests {
         assert_eq!(96593, get_file_size(&file3).unwrap());
     }
 
+    #[test]
+    fn test_dry_run() {
+        let td = get_target_dir();
+        let copier = Copier::new(0).with_dry_run(true);
+        let target_dir = td.clone() + "test_dry_run";
+        ensure_dir_doesnt_exist(&target_dir);
+
+        let stats = copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        copier.copy(&(td.clone() + "../src/test1b"), &target_dir).unwrap();
+        assert!(fs::metadata(&target_dir).is_err(), "A dry run should not create anything");
+        assert_eq!(stats.copied as usize, stats.planned.len());
+        let planned = stats.planned.iter().find(|p| p.source.ends_with("myimg.jpg")).unwrap();
+        assert_eq!(PathBuf::from(target_dir.clone() + "/2019/2019-04-27/myimg.jpg"), planned.target);
+        assert_eq!(DateSource::Gps, planned.date_source);
+        assert!(!planned.date_inferred && !planned.write_exif && !planned.remove_source);
+        assert_eq!(None, planned.suffix);
+
+        // Two different files with the same name planned in the same run get different targets
+        let copier = Copier::new(0).with_dry_run(true);
+        let plan_a = copier.plan_file(Path::new(&(td.clone() + "../src/test1a/myimg.jpg")), Path::new(&target_dir), None)
+            .unwrap().0.unwrap();
+        let plan_b = copier.plan_file(Path::new(&(td.clone() + "../src/test1b/myimg.jpg")), Path::new(&target_dir), None)
+            .unwrap().0.unwrap();
+        assert_eq!(PathBuf::from(target_dir.clone() + "/2019/2019-04-27/myimg.jpg"), plan_a.target);
+        assert_eq!(PathBuf::from(target_dir.clone() + "/2019/2019-04-27/myimg_001.jpg"), plan_b.target);
+        assert_eq!(1, plan_b.counter);
+        assert_eq!(Some(1), copier.planned_copy(&plan_b).suffix);
+
+        // Planning the same file again finds the identical planned file
+        assert!(copier.plan_file(Path::new(&(td.clone() + "../src/test1b/myimg.jpg")), Path::new(&target_dir), None)
+            .unwrap().0.is_none());
+    }
+
+    #[test]
+    fn test_same_size_different_content() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_same_size_src";
+        let target_dir = td.clone() + "test_same_size";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        fs::create_dir_all(source_dir.clone() + "/a").unwrap();
+        fs::create_dir_all(source_dir.clone() + "/b").unwrap();
+
+        // Same name, same size, same date, but one byte of the image data differs
+        let mut data = fs::read(td.clone() + "../src/test/gps-date.jpg").unwrap();
+        fs::write(source_dir.clone() + "/a/img.jpg", &data).unwrap();
+        let last = data.len() - 3;
+        data[last] ^= 0xff;
+        fs::write(source_dir.clone() + "/b/img.jpg", &data).unwrap();
+
+        let copier = Copier::new(0);
+        copier.copy(&(source_dir.clone() + "/a"), &target_dir).unwrap();
+        let stats = copier.copy(&(source_dir.clone() + "/b"), &target_dir).unwrap();
+        assert_eq!(CopyStats { scanned: 1, copied: 1, renamed: 1, dates_from_metadata: 1, ..Default::default() }, stats);
+
+        let subdir = target_dir.clone() + "/2019/2019-04-27";
+        dir_exact(&subdir, &["img.jpg", "img_001.jpg"]);
+        assert_files_equal(source_dir.clone() + "/a/img.jpg", subdir.clone() + "/img.jpg");
+        assert_files_equal(source_dir.clone() + "/b/img.jpg", subdir.clone() + "/img_001.jpg");
+
+        // Copying again doesn't add anything
+        let stats = copier.copy(&(source_dir.clone() + "/b"), &target_dir).unwrap();
+        dir_exact(&subdir, &["img.jpg", "img_001.jpg"]);
+        assert_eq!(1, stats.skipped_identical);
+        assert_eq!(0, stats.copied);
+    }
+
+    #[test]
+    fn test_import_index() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_import_index_src";
+        let target_dir = td.clone() + "test_import_index";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+
+        let copier = Copier::new(0).with_index(true);
+        copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+
+        // The same content under another name is not imported again
+        fs::create_dir_all(&source_dir).unwrap();
+        fs::copy(td.clone() + "../src/test1a/myimg.jpg", source_dir.clone() + "/renamed.jpg").unwrap();
+        let stats = copier.copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.skipped_already_imported);
+        let subdir = target_dir.clone() + "/2019/2019-04-27";
+        dir_exact(&subdir, &["myimg.jpg"]);
+
+        // Also not when the index is loaded again
+        Copier::new(0).with_index(true).copy(&source_dir, &target_dir).unwrap();
+        dir_exact(&subdir, &["myimg.jpg"]);
+
+        // Without the index it is copied
+        Copier::new(0).copy(&source_dir, &target_dir).unwrap();
+        dir_exact(&subdir, &["myimg.jpg", "renamed.jpg"]);
+
+        // When the stored file was edited since, moving copies the source instead of removing it
+        fs::remove_file(subdir.clone() + "/renamed.jpg").unwrap();
+        let mut edited = fs::read(subdir.clone() + "/myimg.jpg").unwrap();
+        edited.extend_from_slice(b"edited");
+        fs::write(subdir.clone() + "/myimg.jpg", edited).unwrap();
+        let stats = Copier::new(0).with_index(true).with_move(true).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(0, stats.skipped_already_imported);
+        assert_eq!(1, stats.copied);
+        dir_exact(&subdir, &["myimg.jpg", "renamed.jpg"]);
+        assert_files_equal(td.clone() + "../src/test1a/myimg.jpg", subdir.clone() + "/renamed.jpg");
+    }
+
+    #[test]
+    fn test_import_index_existing_library() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_import_index_library_src";
+        let target_dir = td.clone() + "test_import_index_library";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+
+        // A library filled without the index gets one the first time it is used
+        Copier::new(0).copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        fs::create_dir_all(&source_dir).unwrap();
+        fs::copy(td.clone() + "../src/test1a/myimg.jpg", source_dir.clone() + "/renamed.jpg").unwrap();
+        let stats = Copier::new(0).with_index(true).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.skipped_already_imported);
+        dir_exact(&(target_dir.clone() + "/2019/2019-04-27"), &["myimg.jpg"]);
+        dir_has_file(&target_dir, ".phototools-index");
+    }
+
+    #[test]
+    fn test_import_index_exif_written() {
+        let td = get_target_dir();
+        let sd = td.clone() + "../src/test3";
+        let target_dir = td.clone() + "test_import_index_exif";
+        ensure_dir_doesnt_exist(&target_dir);
+
+        // The stored photo got EXIF dates, it is still recognised as imported
+        let stats = Copier::new(0).with_index(true).copy(&sd, &target_dir).unwrap();
+        assert_eq!(1, stats.exif_written);
+        let stats = Copier::new(0).with_index(true).copy(&sd, &target_dir).unwrap();
+        assert_eq!(1, stats.skipped_already_imported);
+        assert_eq!(0, stats.copied);
+        let created: DateTime<Utc> = DateTime::from(fs::metadata(sd.clone() + "/NO_METADATA.JPEG").unwrap()
+            .created().unwrap());
+        let subdir = target_dir.clone() + &created.format("/%Y/%Y-%m-%d").to_string();
+        dir_exact(&subdir, &["NO_METADATA.JPEG"]);
+
+        // Moving it removes the source
+        let source_dir = td.clone() + "test_import_index_exif_src";
+        ensure_dir_doesnt_exist(&source_dir);
+        fs::create_dir_all(&source_dir).unwrap();
+        fs::copy(sd.clone() + "/NO_METADATA.JPEG", source_dir.clone() + "/NO_METADATA.JPEG").unwrap();
+        let stats = Copier::new(0).with_index(true).with_move(true).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.skipped_already_imported);
+        dir_exact(&source_dir, &[]);
+        dir_exact(&subdir, &["NO_METADATA.JPEG"]);
+    }
+
+    #[test]
+    fn test_move() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_move_src";
+        let target_dir = td.clone() + "test_move";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        fs::create_dir_all(source_dir.clone() + "/a").unwrap();
+        fs::create_dir_all(source_dir.clone() + "/b").unwrap();
+        fs::copy(td.clone() + "../src/test1a/myimg.jpg", source_dir.clone() + "/a/myimg.jpg").unwrap();
+        fs::copy(td.clone() + "../src/test1b/myimg.jpg", source_dir.clone() + "/b/myimg.jpg").unwrap();
+
+        // A dry run leaves everything in place
+        Copier::new(0).with_move(true).with_dry_run(true).copy(&source_dir, &target_dir).unwrap();
+        assert!(fs::metadata(source_dir.clone() + "/a/myimg.jpg").is_ok());
+
+        let copier = Copier::new(0).with_move(true);
+        copier.copy(&source_dir, &target_dir).unwrap();
+        let subdir = target_dir.clone() + "/2019/2019-04-27";
+        dir_exact(&subdir, &["myimg.jpg", "myimg_001.jpg"]);
+        dir_exact(&(source_dir.clone() + "/a"), &[]);
+        dir_exact(&(source_dir.clone() + "/b"), &[]);
+
+        // A source that is already in the destination is removed too
+        fs::copy(td.clone() + "../src/test1a/myimg.jpg", source_dir.clone() + "/a/myimg.jpg").unwrap();
+        copier.copy(&source_dir, &target_dir).unwrap();
+        dir_exact(&subdir, &["myimg.jpg", "myimg_001.jpg"]);
+        dir_exact(&(source_dir.clone() + "/a"), &[]);
+    }
+
+    #[test]
+    fn test_link_modes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_link_modes_src";
+        ensure_dir_doesnt_exist(&source_dir);
+        fs::create_dir_all(&source_dir).unwrap();
+        let source = source_dir.clone() + "/NO_METADATA.JPEG";
+        fs::copy(td.clone() + "../src/test3/NO_METADATA.JPEG", &source).unwrap();
+        let modified = FileTime::from_unix_time(1_500_033_600, 0);
+        filetime::set_file_times(&source, modified, modified).unwrap();
+        let original = fs::read(&source).unwrap();
+        // The file time decides the folder: 2017-07-14 12:00 UTC
+        let day_dir = "/2017/2017-07-14/";
+
+        let target_dir = td.clone() + "test_link_modes_hard";
+        ensure_dir_doesnt_exist(&target_dir);
+        let stats = Copier::new(0).with_link_mode(LinkMode::Hard).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.copied);
+        assert_eq!(0, stats.exif_written, "Writing EXIF would change the source");
+        let target = target_dir.clone() + day_dir + "NO_METADATA.JPEG";
+        assert_eq!(fs::metadata(&source).unwrap().ino(), fs::metadata(&target).unwrap().ino());
+
+        let target_dir = td.clone() + "test_link_modes_sym";
+        ensure_dir_doesnt_exist(&target_dir);
+        Copier::new(0).with_link_mode(LinkMode::Symbolic).copy(&source_dir, &target_dir).unwrap();
+        let target = target_dir.clone() + day_dir + "NO_METADATA.JPEG";
+        assert_eq!(fs::canonicalize(&source).unwrap(), fs::read_link(&target).unwrap());
+        // Copying again finds the link identical
+        let stats = Copier::new(0).with_link_mode(LinkMode::Symbolic).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.skipped_identical);
+        assert!(Copier::new(0).with_link_mode(LinkMode::Symbolic).with_move(true)
+            .copy(&source_dir, &target_dir).is_err());
+
+        assert_eq!(original, fs::read(&source).unwrap());
+        assert_eq!(modified, FileTime::from_last_modification_time(&fs::metadata(&source).unwrap()));
+
+        // Moving into a hard link leaves the target in place of the source
+        let target_dir = td.clone() + "test_link_modes_move";
+        ensure_dir_doesnt_exist(&target_dir);
+        Copier::new(0).with_link_mode(LinkMode::Hard).with_move(true).copy(&source_dir, &target_dir).unwrap();
+        assert!(fs::metadata(&source).is_err());
+        assert_eq!(original, fs::read(target_dir.clone() + day_dir + "NO_METADATA.JPEG").unwrap());
+    }
+
+    #[test]
+    fn test_file_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_attributes_src";
+        ensure_dir_doesnt_exist(&source_dir);
+        fs::create_dir_all(&source_dir).unwrap();
+        let source = source_dir.clone() + "/gps-date.jpg";
+        fs::copy(td.clone() + "../src/test/gps-date.jpg", &source).unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o640)).unwrap();
+        let has_xattrs = xattr::set(Path::new(&source), "user.phototools.test", b"value").is_ok();
+        let modified = FileTime::from_unix_time(1_500_033_600, 0);
+        filetime::set_file_mtime(&source, modified).unwrap();
+        // Reading the file may change it, so it's taken after the copies
+        let source_atime = || FileTime::from_last_access_time(&fs::metadata(&source).unwrap());
+        let target_times = |target_dir: &str| {
+            let md = fs::metadata(target_dir.to_string() + "/2019/2019-04-27/gps-date.jpg").unwrap();
+            (FileTime::from_last_access_time(&md), FileTime::from_last_modification_time(&md), md)
+        };
+
+        let target_dir = td.clone() + "test_attributes_default";
+        ensure_dir_doesnt_exist(&target_dir);
+        Copier::new(0).copy(&source_dir, &target_dir).unwrap();
+        let (atime, mtime, _) = target_times(&target_dir);
+        assert_eq!(atime, mtime);
+        assert_ne!(modified, mtime);
+        let capture_time = mtime;
+
+        let target_dir = td.clone() + "test_attributes_mtime";
+        ensure_dir_doesnt_exist(&target_dir);
+        Copier::new(0).with_set_times(SetTimes::Modified).copy(&source_dir, &target_dir).unwrap();
+        let (atime, mtime, _) = target_times(&target_dir);
+        assert_eq!((source_atime(), capture_time), (atime, mtime));
+
+        let target_dir = td.clone() + "test_attributes_preserved";
+        ensure_dir_doesnt_exist(&target_dir);
+        Copier::new(0).with_set_times(SetTimes::Neither).with_preserve_attributes(true).with_keep_original_mtime(true)
+            .copy(&source_dir, &target_dir).unwrap();
+        let (atime, mtime, md) = target_times(&target_dir);
+        assert_eq!((source_atime(), modified), (atime, mtime));
+        assert_eq!(0o640, md.permissions().mode() & 0o777);
+        if has_xattrs {
+            let target = PathBuf::from(target_dir.clone() + "/2019/2019-04-27/gps-date.jpg");
+            assert_eq!(Some(b"value".to_vec()), xattr::get(&target, "user.phototools.test").unwrap());
+            assert_eq!(Some(b"2017-07-14T12:00:00Z".to_vec()), xattr::get(&target, ORIGINAL_MTIME_ATTR).unwrap());
+
+            // Copying the copy keeps the first original time
+            let target_dir2 = td.clone() + "test_attributes_again";
+            ensure_dir_doesnt_exist(&target_dir2);
+            Copier::new(0).with_keep_original_mtime(true).copy(&target_dir, &target_dir2).unwrap();
+            let target2 = PathBuf::from(target_dir2 + "/2019/2019-04-27/gps-date.jpg");
+            assert_eq!(Some(b"2017-07-14T12:00:00Z".to_vec()), xattr::get(&target2, ORIGINAL_MTIME_ATTR).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let td = get_target_dir();
+        let target_dir = td.clone() + "test_layout";
+        ensure_dir_doesnt_exist(&target_dir);
+
+        let copier = Copier::new(0).with_layout(Template::parse("{model}/{YYYY}/{MM}/{DD}").unwrap());
+        copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        copier.copy(&(td.clone() + "../src/test2"), &target_dir).unwrap();
+        dir_exact(&(target_dir.clone() + "/H3113/2019/04/27"), &["myimg.jpg"]);
+        let iphone_dir = fs::read_dir(&target_dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .find(|n| n.starts_with("iPhone"))
+            .unwrap();
+        dir_exact(&format!("{}/{}/2018/06/02", target_dir, iphone_dir), &["FROM_IPHONE.MOV"]);
+
+        let copier = Copier::new(0).with_layout(Template::parse("{type}/{YYYY}/Q{Q}").unwrap());
+        copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        dir_exact(&(target_dir.clone() + "/photo/2019/Q2"), &["myimg.jpg"]);
+    }
+
+    #[test]
+    fn test_raw_formats() {
+        let td = get_target_dir();
+        let target_dir = td.clone() + "test_raw_formats";
+        ensure_dir_doesnt_exist(&target_dir);
+
+        let copier = Copier::new(0).with_layout(Template::parse("{make}/{YYYY}-{MM}-{DD}").unwrap());
+        let stats = copier.copy(&(td.clone() + "../src/testraw"), &target_dir).unwrap();
+        assert_eq!(7, stats.copied);
+        assert_eq!(7, stats.dates_from_metadata);
+        dir_exact(&(target_dir.clone() + "/Canon/2021-06-15"), &["canon.cr2", "canon.cr3"]);
+        dir_exact(&(target_dir.clone() + "/Panasonic/2021-06-15"), &["panasonic.rw2"]);
+    }
+
+    #[test]
+    fn test_content_sniffing() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_content_sniffing_src";
+        let target_dir = td.clone() + "test_content_sniffing";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        fs::create_dir_all(&source_dir).unwrap();
+        // A photo without extension and one with the extension of a movie
+        fs::copy(td.clone() + "../src/test/gps-date.jpg", source_dir.clone() + "/IMG_0001").unwrap();
+        fs::copy(td.clone() + "../src/test/gps-date.jpg", source_dir.clone() + "/recovered.mov").unwrap();
+
+        let stats = Copier::new(0).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(2, stats.copied);
+        assert_eq!(2, stats.dates_from_metadata);
+        dir_exact(&(target_dir.clone() + "/2019/2019-04-27"), &["IMG_0001", "recovered.mov"]);
+    }
+
+    #[test]
+    fn test_pairing() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_pairing_src";
+        let target_dir = td.clone() + "test_pairing";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        fs::create_dir_all(&source_dir).unwrap();
+        let add = |from: &str, name: &str| fs::copy(td.clone() + "../src/" + from, source_dir.clone() + "/" + name).unwrap();
+        // A RAW+JPEG pair with sidecars, the JPEG has a different date
+        add("testraw/canon.cr2", "IMG_1234.CR2");
+        add("test/gps-date.jpg", "IMG_1234.JPG");
+        fs::write(source_dir.clone() + "/IMG_1234.CR2.xmp", "<x:xmpmeta/>").unwrap();
+        fs::write(source_dir.clone() + "/IMG_1234.AAE", "<plist/>").unwrap();
+        // A Live Photo, the movie has a different date
+        add("test/heic/image1.heic", "IMG_0001.HEIC");
+        add("test2/FROM_IPHONE.MOV", "IMG_0001.MOV");
+        fs::write(source_dir.clone() + "/lonely.xmp", "<x:xmpmeta/>").unwrap();
+        // Another file already has the name of the RAW file
+        let day_dir = target_dir.clone() + "/2021/2021-06-15";
+        fs::create_dir_all(&day_dir).unwrap();
+        fs::write(day_dir.clone() + "/IMG_1234.CR2", "other").unwrap();
+
+        let copier = Copier::new(0);
+        let stats = copier.copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(6, stats.copied);
+        // Only the RAW file collided, the others follow its name
+        assert_eq!(1, stats.renamed);
+        assert_eq!(1, stats.skipped_unsupported);
+        dir_exact(&day_dir, &["IMG_1234.CR2", "IMG_1234_001.CR2", "IMG_1234_001.JPG", "IMG_1234_001.CR2.xmp", "IMG_1234_001.AAE"]);
+        dir_exact(&(target_dir.clone() + "/2023/2023-02-18"), &["IMG_0001.HEIC", "IMG_0001.MOV"]);
+
+        let stats = copier.copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(0, stats.copied);
+        assert_eq!(6, stats.skipped_identical);
+    }
+
+    #[test]
+    fn test_filter() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_filter_src";
+        let target_dir = td.clone() + "test_filter";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        for dir in ["DCIM/.thumbnails", "DCIM/.Trash", "@eaDir", "DCIM/cache"] {
+            fs::create_dir_all(source_dir.clone() + "/" + dir).unwrap();
+        }
+        let add = |name: &str| fs::copy(td.clone() + "../src/test/gps-date.jpg", source_dir.clone() + "/" + name).unwrap();
+        add("DCIM/IMG_0001.JPG");
+        add("DCIM/.trashed-1690000000-IMG_0002.JPG");
+        add("DCIM/.thumbnails/IMG_0001.JPG");
+        add("DCIM/.Trash/IMG_0003.JPG");
+        add("@eaDir/IMG_0001.JPG");
+        add("DCIM/cache/IMG_0004.JPG");
+        fs::copy(td.clone() + "../src/testraw/canon.cr2", source_dir.clone() + "/DCIM/IMG_0005.CR2").unwrap();
+
+        let stats = Copier::new(0).copy(&source_dir, &target_dir).unwrap();
+        // Only the files in hidden directories are left out
+        assert_eq!(CopyStats { scanned: 4, copied: 3, skipped_hidden: 1, dates_from_metadata: 3,
+            ..Default::default() }, stats);
+
+        let target_dir = td.clone() + "test_filter_patterns";
+        ensure_dir_doesnt_exist(&target_dir);
+        let filter = PathFilter::new(&["*.jpg".to_string()], &["DCIM/cache".to_string()], true).unwrap();
+        let stats = Copier::new(0).with_filter(filter).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(6, stats.scanned);
+        assert_eq!(3, stats.copied);
+        // The thumbnail copies are the same photo
+        assert_eq!(2, stats.skipped_identical);
+        // The CR2 file, the cache directory isn't looked into
+        assert_eq!(1, stats.skipped_excluded);
+        dir_exact(&(target_dir.clone() + "/2019/2019-04-27"), &["IMG_0001.JPG", ".trashed-1690000000-IMG_0002.JPG", "IMG_0003.JPG"]);
+    }
+
+    #[test]
+    fn test_date_range() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_date_range_src";
+        let target_dir = td.clone() + "test_date_range";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        fs::create_dir_all(&source_dir).unwrap();
+        let add = |from: &str, name: &str| fs::copy(td.clone() + "../src/" + from, source_dir.clone() + "/" + name).unwrap();
+        add("testraw/canon.cr2", "IMG_1234.CR2");
+
+        let range = DateRange::parse(Some("last"), None).unwrap();
+        let stats = Copier::new(0).with_index(true).with_date_range(range).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.copied);
+        assert_eq!(1, stats.dates_from_metadata);
+        let last_import = "Canon EOS 5D Mark IV\t2021-06-15T10:00:01\n";
+        assert_eq!(last_import, fs::read_to_string(target_dir.clone() + "/.phototools-last-import").unwrap());
+
+        // Other bounds don't move the last imports forward, as earlier files may have been left out
+        add("test/gps-date.jpg", "gps-date.jpg");
+        add("test/heic/image1.heic", "IMG_0001.HEIC");
+        let bounded = DateRange::parse(Some("2023"), None).unwrap();
+        let stats = Copier::new(0).with_index(true).with_date_range(bounded).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.copied);
+        assert_eq!(2, stats.skipped_out_of_range);
+        dir_exact(&(target_dir.clone() + "/2023/2023-02-18"), &["IMG_0001.HEIC"]);
+        assert_eq!(last_import, fs::read_to_string(target_dir.clone() + "/.phototools-last-import").unwrap());
+
+        // The RAW file was imported before, the other devices weren't
+        let stats = Copier::new(0).with_index(true).with_date_range(range).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(1, stats.copied);
+        assert_eq!(1, stats.skipped_already_imported);
+        assert_eq!(1, stats.skipped_out_of_range);
+        dir_exact(&(target_dir.clone() + "/2019/2019-04-27"), &["gps-date.jpg"]);
+        dir_exact(&(target_dir.clone() + "/2023/2023-02-18"), &["IMG_0001.HEIC"]);
+
+        // Without the index the last imports are kept too, but not in a dry run
+        let target_dir = td.clone() + "test_date_range_no_index";
+        ensure_dir_doesnt_exist(&target_dir);
+        let stats = Copier::new(0).with_dry_run(true).with_date_range(range).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(3, stats.copied);
+        assert!(!Path::new(&(target_dir.clone() + "/.phototools-last-import")).exists());
+        let stats = Copier::new(0).with_date_range(range).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(3, stats.copied);
+        let stats = Copier::new(0).with_date_range(range).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(0, stats.copied);
+        assert_eq!(3, stats.skipped_out_of_range);
+    }
+
+    #[test]
+    fn test_video_formats() {
+        let td = get_target_dir();
+        let target_dir = td.clone() + "test_video_formats";
+        ensure_dir_doesnt_exist(&target_dir);
+
+        let copier = Copier::new(0).with_timezone(TimezoneMode::Utc);
+        let stats = copier.copy(&(td.clone() + "../src/testvideo"), &target_dir).unwrap();
+        assert_eq!(5, stats.copied);
+        assert_eq!(4, stats.dates_from_metadata);
+        dir_exact(&(target_dir.clone() + "/2010/2010-03-02"), &["camera.avi"]);
+        dir_exact(&(target_dir.clone() + "/2012/2012-07-14"), &["camcorder.mts"]);
+        dir_exact(&(target_dir.clone() + "/2022/2022-11-05"), &["screen.mkv"]);
+        dir_exact(&(target_dir.clone() + "/2009/2009-08-20"), &["phone.3gp"]);
+    }
+
+    #[test]
+    fn test_image_formats() {
+        let td = get_target_dir();
+        let target_dir = td.clone() + "test_image_formats";
+        ensure_dir_doesnt_exist(&target_dir);
+
+        let copier = Copier::new(0).with_timezone(TimezoneMode::Utc);
+        let stats = copier.copy(&(td.clone() + "../src/testimage"), &target_dir).unwrap();
+        assert_eq!(6, stats.copied);
+        assert_eq!(0, stats.skipped_unsupported);
+        assert_eq!(5, stats.dates_from_metadata);
+        dir_exact(&(target_dir.clone() + "/2020/2020-01-02"), &["photo.png"]);
+        dir_exact(&(target_dir.clone() + "/2020/2020-02-03"), &["sticker.webp"]);
+        dir_exact(&(target_dir.clone() + "/2023/2023-03-14"), &["screenshot.png"]);
+    }
+
+    #[test]
+    fn test_rename() {
+        let td = get_target_dir();
+        let target_dir = td.clone() + "test_rename";
+        ensure_dir_doesnt_exist(&target_dir);
+
+        let rename = Template::parse_file_name("{date}_{time}_{name}.{ext}").unwrap();
+        let copier = Copier::new(0).with_rename(Some(rename));
+        copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        copier.copy(&(td.clone() + "../src/test1b"), &target_dir).unwrap();
+        copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        let subdir = target_dir.clone() + "/2019/2019-04-27";
+        dir_exact(&subdir, &["20190427_150801_myimg.jpg", "20190427_150801_myimg_001.jpg"]);
+
+        // With {seq} the sequence number is used instead of a suffix
+        let rename = Template::parse_file_name("{date}_{time}_{camera}_{seq}.{ext}").unwrap();
+        let copier = Copier::new(0).with_rename(Some(rename));
+        copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        copier.copy(&(td.clone() + "../src/test1b"), &target_dir).unwrap();
+        copier.copy(&(td.clone() + "../src/test1b"), &target_dir).unwrap();
+        dir_exact(&subdir, &["20190427_150801_myimg.jpg", "20190427_150801_myimg_001.jpg",
+            "20190427_150801_H3113_001.jpg", "20190427_150801_H3113_002.jpg"]);
+        assert_files_equal(td.clone() + "../src/test1b/myimg.jpg", subdir.clone() + "/20190427_150801_H3113_002.jpg");
+    }
+
+    #[test]
+    fn test_timezone() {
+        let td = get_target_dir();
+        let target_dir = td.clone() + "test_timezone";
+        ensure_dir_doesnt_exist(&target_dir);
+
+        // The photo was taken at 15:08:01 UTC+1
+        let rename = Template::parse_file_name("{date}_{time}.{ext}").unwrap();
+        let copier = Copier::new(0).with_rename(Some(rename.clone())).with_timezone(TimezoneMode::Utc);
+        copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        let copier = Copier::new(0).with_rename(Some(rename))
+            .with_timezone(TimezoneMode::parse("+10:00").unwrap());
+        copier.copy(&(td.clone() + "../src/test1a"), &target_dir).unwrap();
+        dir_exact(&(target_dir.clone() + "/2019/2019-04-27"), &["20190427_140801.jpg"]);
+        dir_exact(&(target_dir.clone() + "/2019/2019-04-28"), &["20190428_000801.jpg"]);
+
+        // The file time is the moment the photo was taken
+        let md = fs::metadata(target_dir.clone() + "/2019/2019-04-28/20190428_000801.jpg").unwrap();
+        let modified: DateTime<Utc> = DateTime::from(md.modified().unwrap());
+        assert_eq!("2019-04-27 14:08:01", modified.format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+
+    #[test]
+    fn test_manifest() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_manifest_src";
+        let target_dir = td.clone() + "test_manifest";
+        let manifest = td.clone() + "test_manifest.jsonl";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        fs::create_dir_all(&source_dir).unwrap();
+        fs::copy(td.clone() + "../src/test1a/myimg.jpg", source_dir.clone() + "/myimg.jpg").unwrap();
+        fs::write(source_dir.clone() + "/.hidden.jpg", b"hidden").unwrap();
+        fs::write(source_dir.clone() + "/notes.txt", b"notes").unwrap();
+
+        let copier = Copier::new(0).with_manifest(Some(PathBuf::from(&manifest)));
+        copier.copy(&source_dir, &target_dir).unwrap();
+        let mut lines: Vec<String> = fs::read_to_string(&manifest).unwrap().lines().map(String::from).collect();
+        lines.sort();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with(&format!("{{\"source\":\"{}/.hidden.jpg\",\"target\":null,\"action\":\"skipped\",\
+            \"reason\":\"hidden\"", source_dir)), "{}", lines[0]);
+        assert_eq!(format!("{{\"source\":\"{}/myimg.jpg\",\"target\":\"{}/2019/2019-04-27/myimg.jpg\",\
+            \"action\":\"copied\",\"reason\":\"new\",\"timestamp\":\"2019-04-27T15:08:01+01:00\",\"timestamp_source\":\"gps\",\
+            \"size\":204636,\"hash\":\"{}\"}}", source_dir, target_dir, hash::hash_file(source_dir.clone() + "/myimg.jpg").unwrap()),
+            lines[1]);
+        assert!(lines[2].contains("\"action\":\"skipped\",\"reason\":\"unsupported file type\""), "{}", lines[2]);
+
+        // The second time the file is a duplicate, and the manifest is replaced
+        copier.copy(&source_dir, &target_dir).unwrap();
+        let content = fs::read_to_string(&manifest).unwrap();
+        assert_eq!(3, content.lines().count());
+        assert!(content.contains("\"action\":\"duplicate\",\"reason\":\"identical file exists\""), "{}", content);
+    }
+
+    #[test]
+    fn test_jobs() {
+        let td = get_target_dir();
+        let source_dir = td.clone() + "test_jobs_src";
+        let target_dir = td.clone() + "test_jobs";
+        ensure_dir_doesnt_exist(&source_dir);
+        ensure_dir_doesnt_exist(&target_dir);
+        for i in 0..8 {
+            let dir = format!("{}/{}", source_dir, i);
+            fs::create_dir_all(&dir).unwrap();
+            let src = if i % 2 == 0 { "../src/test1a/myimg.jpg" } else { "../src/test1b/myimg.jpg" };
+            fs::copy(td.clone() + src, dir.clone() + "/myimg.jpg").unwrap();
+            fs::copy(td.clone() + "../src/test1a/myimg.jpg", format!("{}/other{}.jpg", dir, i)).unwrap();
+        }
+
+        // Same names and same content at the same time still end up as one file per content. Which
+        // name the content of test1a gets depends on which worker was first.
+        let copier = Copier::new(0).with_jobs(4).with_index(true);
+        let stats = copier.copy(&source_dir, &target_dir).unwrap();
+        let subdir = target_dir.clone() + "/2019/2019-04-27";
+        assert_eq!(2, fs::read_dir(&subdir).unwrap().count());
+        assert_eq!(2, stats.copied);
+        assert_eq!(14, stats.skipped_identical + stats.skipped_already_imported);
+
+        // Without the index only same names are detected
+        let target_dir = td.clone() + "test_jobs_no_index";
+        ensure_dir_doesnt_exist(&target_dir);
+        let stats = Copier::new(0).with_jobs(4).copy(&source_dir, &target_dir).unwrap();
+        assert_eq!(10, stats.copied);
+        assert_eq!(6, stats.skipped_identical);
+        assert_eq!(10, fs::read_dir(target_dir + "/2019/2019-04-27").unwrap().count());
+    }
+
+    #[test]
+    fn test_jobs_move() {
+        let td = get_target_dir();
+        for use_index in [true, false] {
+            let source_dir = format!("{}test_jobs_move_src_{}", td, use_index);
+            let target_dir = format!("{}test_jobs_move_{}", td, use_index);
+            ensure_dir_doesnt_exist(&source_dir);
+            ensure_dir_doesnt_exist(&target_dir);
+            for i in 0..16 {
+                let dir = format!("{}/{}", source_dir, i);
+                fs::create_dir_all(&dir).unwrap();
+                fs::copy(td.clone() + "../src/test1a/myimg.jpg", dir.clone() + "/myimg.jpg").unwrap();
+            }
+
+            // A source is only removed once the copy of the same content by another worker is done
+            let copier = Copier::new(0).with_jobs(4).with_index(use_index).with_move(true);
+            let stats = copier.copy(&source_dir, &target_dir).unwrap();
+            assert_eq!(1, stats.copied);
+            assert_eq!(16, stats.sources_removed);
+            let subdir = target_dir.clone() + "/2019/2019-04-27";
+            dir_exact(&subdir, &["myimg.jpg"]);
+            assert_files_equal(td.clone() + "../src/test1a/myimg.jpg", subdir + "/myimg.jpg");
+            for i in 0..16 {
+                dir_exact(&format!("{}/{}", source_dir, i), &[]);
+            }
+        }
+    }
+
     #[test]
     fn test_iphone_mov() {
         let td = get_target_dir();
         assert!(td.ends_with("/phototools/target/"));
 
-        let copier = Copier::new(0, false);
+        let copier = Copier::new(0);
         let sd = td.clone() + "../src/test2";
         let tdp1 = td.clone() + "test_mov";
         ensure_dir_doesnt_exist(&tdp1);
@@ -468,7 +2054,7 @@ mod tests {
         }
     }
 
-    fn get_file_size(p: &str) -> GenResult<u64> {
+    fn get_file_size(p: &str) -> Result<u64> {
         let md = fs::metadata(p)?;
         Ok(md.len())
     }
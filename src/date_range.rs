@@ -0,0 +1,152 @@
+use crate::error::{Error, Result};
+
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+
+/// The value of `--since` that stands for the last import from the same device.
+pub const SINCE_LAST: &str = "last";
+
+/// The start of a date range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Since {
+    Time(NaiveDateTime),
+    /// After the latest capture time imported from the same device before, see LastImports
+    LastImport
+}
+
+/// Which capture times to copy, compared with the local time that decides the folder. Both ends
+/// are inclusive.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DateRange {
+    since: Option<Since>,
+    until: Option<NaiveDateTime>
+}
+
+impl DateRange {
+    /// Parses the bounds, each a year, month, day or time: `2023`, `2023-05`, `2023-05-01` or
+    /// `2023-05-01 12:00[:00]`. The range starts at the beginning of `since` and ends at the end of
+    /// `until`, so `--since 2023 --until 2023` is all of 2023. `since` may also be `last`.
+    pub fn parse(since: Option<&str>, until: Option<&str>) -> Result<DateRange> {
+        let since = match since {
+            Some(SINCE_LAST) => Some(Since::LastImport),
+            Some(s) => Some(Since::Time(DateRange::parse_period(s)?.0)),
+            None => None
+        };
+        let until = match until {
+            Some(s) => Some(DateRange::parse_period(s)?.1),
+            None => None
+        };
+        if let (Some(Since::Time(s)), Some(u)) = (since, until) {
+            if s > u {
+                return Err(Error::InvalidInput(format!("The date range ends before it starts: {} is after {}", s, u)));
+            }
+        }
+        Ok(DateRange { since, until })
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    pub fn since_last_import(&self) -> bool {
+        self.since == Some(Since::LastImport)
+    }
+
+    /// Whether the local capture time is in the range. With `--since last`, the last import time
+    /// of the device the file came from is needed: files from a device that wasn't imported from
+    /// before are all in range.
+    pub fn contains(&self, local_time: NaiveDateTime, last_import: Option<NaiveDateTime>) -> bool {
+        let after_start = match self.since {
+            Some(Since::Time(s)) => local_time >= s,
+            Some(Since::LastImport) => last_import.is_none_or(|l| local_time > l),
+            None => true
+        };
+        after_start && self.until.is_none_or(|u| local_time <= u)
+    }
+
+    // The first and last second of the period
+    fn parse_period(s: &str) -> Result<(NaiveDateTime, NaiveDateTime)> {
+        let s = s.trim();
+        let invalid = || Error::InvalidInput(format!("Invalid date '{}', use e.g. 2023, 2023-05, 2023-05-01 \
+            or 2023-05-01 12:00", s));
+        let midnight = |d: NaiveDate| d.and_hms_opt(0, 0, 0).unwrap();
+        let second = Duration::seconds(1);
+
+        for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"] {
+            if let Ok(dt) = NaiveDateTime::parse_from_str(s, format) {
+                return Ok((dt, dt));
+            }
+        }
+        for format in ["%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"] {
+            if let Ok(dt) = NaiveDateTime::parse_from_str(s, format) {
+                return Ok((dt, dt + Duration::minutes(1) - second));
+            }
+        }
+        if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok((midnight(d), midnight(d) + Duration::days(1) - second));
+        }
+        let parts: Vec<&str> = s.split('-').collect();
+        let number = |p: &str| p.parse::<u32>().ok().filter(|_| p.chars().all(|c| c.is_ascii_digit()));
+        let (start, next) = match parts[..] {
+            [y] if y.len() == 4 => {
+                let y = number(y).ok_or_else(invalid)? as i32;
+                (NaiveDate::from_ymd_opt(y, 1, 1), NaiveDate::from_ymd_opt(y + 1, 1, 1))
+            },
+            [y, m] if y.len() == 4 => {
+                let (y, m) = (number(y).ok_or_else(invalid)? as i32, number(m).ok_or_else(invalid)?);
+                let next = if m == 12 { NaiveDate::from_ymd_opt(y + 1, 1, 1) } else { NaiveDate::from_ymd_opt(y, m + 1, 1) };
+                (NaiveDate::from_ymd_opt(y, m, 1), next)
+            },
+            _ => (None, None)
+        };
+        match (start, next) {
+            (Some(start), Some(next)) => Ok((midnight(start), midnight(next) - second)),
+            _ => Err(invalid())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let range = |since, until| DateRange::parse(since, until).unwrap();
+        assert_eq!(DateRange { since: Some(Since::Time(dt("2023-01-01 00:00:00"))), until: Some(dt("2023-12-31 23:59:59")) },
+            range(Some("2023"), Some("2023")));
+        assert_eq!(DateRange { since: Some(Since::Time(dt("2024-02-01 00:00:00"))), until: Some(dt("2024-02-29 23:59:59")) },
+            range(Some("2024-02"), Some("2024-02")));
+        assert_eq!(DateRange { since: None, until: Some(dt("2023-12-31 23:59:59")) }, range(None, Some("2023-12")));
+        assert_eq!(DateRange { since: Some(Since::Time(dt("2023-05-01 00:00:00"))), until: Some(dt("2023-05-14 23:59:59")) },
+            range(Some("2023-05-01"), Some("2023-05-14")));
+        assert_eq!(DateRange { since: Some(Since::Time(dt("2023-05-01 12:30:00"))), until: Some(dt("2023-05-01 18:00:59")) },
+            range(Some("2023-05-01 12:30"), Some("2023-05-01T18:00")));
+        assert_eq!(DateRange { since: Some(Since::LastImport), until: None }, range(Some("last"), None));
+        assert!(range(None, None).is_unbounded());
+
+        assert!(DateRange::parse(Some("2023-13"), None).is_err());
+        assert!(DateRange::parse(Some("yesterday"), None).is_err());
+        assert!(DateRange::parse(Some("23"), None).is_err());
+        assert!(DateRange::parse(None, Some("last")).is_err());
+        assert!(DateRange::parse(Some("2023-05-02"), Some("2023-05-01")).is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let range = DateRange::parse(Some("2023-05-01"), Some("2023-05-14")).unwrap();
+        assert!(range.contains(dt("2023-05-01 00:00:00"), None));
+        assert!(range.contains(dt("2023-05-14 23:59:59"), None));
+        assert!(!range.contains(dt("2023-04-30 23:59:59"), None));
+        assert!(!range.contains(dt("2023-05-15 00:00:00"), None));
+
+        let range = DateRange::parse(Some(SINCE_LAST), None).unwrap();
+        assert!(range.since_last_import());
+        assert!(range.contains(dt("2000-01-01 00:00:00"), None));
+        assert!(range.contains(dt("2023-05-01 10:00:01"), Some(dt("2023-05-01 10:00:00"))));
+        assert!(!range.contains(dt("2023-05-01 10:00:00"), Some(dt("2023-05-01 10:00:00"))));
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The errors of the phototools library.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file or directory failed. The path is the one involved, if known.
+    Io { path: Option<PathBuf>, source: io::Error },
+    /// The file is not of a kind that can be handled, e.g. EXIF data can't be written to it
+    UnsupportedFormat { path: PathBuf, reason: String },
+    /// The file's metadata is corrupt or has an unexpected value
+    MetadataParse { path: PathBuf, reason: String },
+    /// The target path is taken by something that can't be replaced
+    DestinationConflict { path: PathBuf, reason: String },
+    /// An invalid option value, such as a template with an unknown token
+    InvalidInput(String),
+    /// A record could not be written as JSON
+    Json(serde_json::Error)
+}
+
+impl Error {
+    pub fn io<P: AsRef<Path>>(path: P, source: io::Error) -> Error {
+        Error::Io { path: Some(path.as_ref().to_path_buf()), source }
+    }
+
+    pub fn unsupported<P: AsRef<Path>>(path: P, reason: &str) -> Error {
+        Error::UnsupportedFormat { path: path.as_ref().to_path_buf(), reason: reason.to_string() }
+    }
+
+    pub fn metadata<P: AsRef<Path>>(path: P, reason: &str) -> Error {
+        Error::MetadataParse { path: path.as_ref().to_path_buf(), reason: reason.to_string() }
+    }
+
+    pub fn conflict<P: AsRef<Path>>(path: P, reason: &str) -> Error {
+        Error::DestinationConflict { path: path.as_ref().to_path_buf(), reason: reason.to_string() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path: Some(p), source } => write!(f, "{}: {}", p.to_string_lossy(), source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+            Error::UnsupportedFormat { path, reason } =>
+                write!(f, "Unsupported format {}: {}", path.to_string_lossy(), reason),
+            Error::MetadataParse { path, reason } =>
+                write!(f, "Invalid metadata in {}: {}", path.to_string_lossy(), reason),
+            Error::DestinationConflict { path, reason } =>
+                write!(f, "Cannot write {}: {}", path.to_string_lossy(), reason),
+            Error::InvalidInput(msg) => write!(f, "{}", msg),
+            Error::Json(e) => write!(f, "Unable to write JSON: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Json(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io { path: None, source: e }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let e = Error::io("/a/b.jpg", io::Error::new(io::ErrorKind::NotFound, "not found"));
+        assert_eq!("/a/b.jpg: not found", e.to_string());
+        assert!(std::error::Error::source(&e).is_some());
+        assert_eq!("Unsupported format x.heic: no EXIF writer",
+            Error::unsupported("x.heic", "no EXIF writer").to_string());
+        assert_eq!("Invalid metadata in x.jpg: bad date", Error::metadata("x.jpg", "bad date").to_string());
+        assert_eq!("Cannot write y.jpg: exists", Error::conflict("y.jpg", "exists").to_string());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::error::{Error, Result};
+use crate::timestamp::Timestamp;
+
+use chrono::NaiveDate;
+use log::debug;
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// The time used when a file name only has a date.
+const DEFAULT_HOUR: u32 = 13;
+
+/// The built-in patterns, in the order they are tried: name, regular expression and whether the
+/// time in the name is UTC.
+const BUILTIN: [(&str, &str, bool); 6] = [
+    // IMG-20170701-WA0002.jpg, VID-20181129-WA9876.mp4
+    ("whatsapp", r"^(?:IMG|VID|AUD|PTT|STK)-(?P<year>\d{4})(?P<month>\d\d)(?P<day>\d\d)-WA\d+", false),
+    // signal-2023-01-01-123456.jpg, signal-2023-01-01-12-34-56-789.jpg
+    ("signal", r"^signal-(?P<year>\d{4})-(?P<month>\d\d)-(?P<day>\d\d)-(?P<hour>\d\d)-?(?P<minute>\d\d)-?(?P<second>\d\d)", false),
+    // photo_2023-01-01_12-34-56.jpg, video_2023-01-01_12-34-56.mp4
+    ("telegram", r"^(?:photo|video|file)_(?P<year>\d{4})-(?P<month>\d\d)-(?P<day>\d\d)_(?P<hour>\d\d)-(?P<minute>\d\d)-(?P<second>\d\d)", false),
+    // PXL_20230101_123456789.jpg, Pixel phones name files after the UTC time
+    ("pixel", r"^PXL_(?P<year>\d{4})(?P<month>\d\d)(?P<day>\d\d)_(?P<hour>\d\d)(?P<minute>\d\d)(?P<second>\d\d)", true),
+    // Screenshot_2023-01-01-12-00-00.png, Screenshot_20230101-120000.png
+    ("screenshot", r"^Screenshot_(?P<year>\d{4})-?(?P<month>\d\d)-?(?P<day>\d\d)[-_](?P<hour>\d\d)-?(?P<minute>\d\d)-?(?P<second>\d\d)", false),
+    // 20230101_123456.mp4, IMG_20230101_123456.jpg
+    ("date_time", r"(?:^|\D)(?P<year>(?:19|20)\d\d)(?P<month>\d\d)(?P<day>\d\d)_(?P<hour>\d\d)(?P<minute>\d\d)(?P<second>\d\d)(?:\D|$)", false)
+];
+
+/// A regular expression that finds a date in a file name, with the named groups `year`, `month`
+/// and `day`, and optionally `hour` and `minute`, with or without `second`.
+#[derive(Clone, Debug)]
+pub struct FilenamePattern {
+    name: String,
+    regex: Regex,
+    utc: bool
+}
+
+impl FilenamePattern {
+    /// A pattern from the configuration. The time in the name is taken to be a local time.
+    pub fn custom(regex: &str) -> Result<FilenamePattern> {
+        let invalid = |reason: &str| Error::InvalidInput(format!("Invalid file name pattern '{}': {}", regex, reason));
+        let compiled = Regex::new(regex).map_err(|e| invalid(&e.to_string()))?;
+        let groups: Vec<&str> = compiled.capture_names().flatten().collect();
+        if let Some(missing) = ["year", "month", "day"].iter().find(|g| !groups.contains(g)) {
+            return Err(invalid(&format!("the named group '{}' is missing", missing)));
+        }
+        // A time needs at least the hour and minute, the seconds are optional
+        for (group, needs) in [("hour", "minute"), ("minute", "hour"), ("second", "minute")] {
+            if groups.contains(&group) && !groups.contains(&needs) {
+                return Err(invalid(&format!("the named group '{}' needs a group '{}'", group, needs)));
+            }
+        }
+        Ok(FilenamePattern { name: regex.to_string(), regex: compiled, utc: false })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn date_time(&self, file_name: &str) -> Option<Timestamp> {
+        let c = self.regex.captures(file_name)?;
+        let number = |group: &str| -> Option<u32> { c.name(group)?.as_str().parse().ok() };
+        let date = NaiveDate::from_ymd_opt(number("year")? as i32, number("month")?, number("day")?)?;
+        let date_time = match c.name("hour") {
+            Some(_) => date.and_hms_opt(number("hour")?, number("minute")?, number("second").unwrap_or(0))?,
+            None => date.and_hms_opt(DEFAULT_HOUR, 0, 0)?
+        };
+        Some(if self.utc { Timestamp::utc(date_time) } else { Timestamp::floating(date_time) })
+    }
+}
+
+/// The patterns that are tried, in order, to find a date in the name of a file without date metadata.
+#[derive(Clone, Debug)]
+pub struct FilenameDates {
+    patterns: Vec<FilenamePattern>
+}
+
+impl FilenameDates {
+    /// The built-in patterns: WhatsApp, Signal, Telegram, Pixel, Android screenshots and names
+    /// with `YYYYMMDD_hhmmss`.
+    pub fn builtin() -> &'static FilenameDates {
+        static BUILTIN_DATES: OnceLock<FilenameDates> = OnceLock::new();
+        BUILTIN_DATES.get_or_init(|| FilenameDates::with_custom(Vec::new()))
+    }
+
+    /// The custom patterns, which are tried first, followed by the built-in ones.
+    pub fn with_custom(custom: Vec<FilenamePattern>) -> FilenameDates {
+        let builtin = BUILTIN.iter().map(|(name, regex, utc)| FilenamePattern {
+            name: name.to_string(),
+            regex: Regex::new(regex).unwrap(),
+            utc: *utc
+        });
+        FilenameDates { patterns: custom.into_iter().chain(builtin).collect() }
+    }
+
+    /// The date and time in the file name, and the name of the pattern that found it.
+    pub fn date_time(&self, file_name: &str) -> Option<(Timestamp, &str)> {
+        self.patterns.iter().find_map(|p| {
+            let ts = p.date_time(file_name)?;
+            debug!("Found date {} in file name {} with pattern {}", ts, file_name, p.name());
+            Some((ts, p.name()))
+        })
+    }
+}
+
+impl Default for FilenameDates {
+    fn default() -> FilenameDates {
+        FilenameDates::builtin().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(file_name: &str) -> Option<(String, &'static str)> {
+        FilenameDates::builtin().date_time(file_name).map(|(ts, name)| (ts.to_string(), name))
+    }
+
+    #[test]
+    fn test_builtin() {
+        let expect = |ts: &str, name: &'static str| Some((ts.to_string(), name));
+        assert_eq!(expect("2017-07-01T13:00:00", "whatsapp"), found("IMG-20170701-WA0002.jpg"));
+        assert_eq!(expect("2018-11-29T13:00:00", "whatsapp"), found("VID-20181129-WA9876.mp4"));
+        assert_eq!(expect("2023-01-01T12:34:56", "signal"), found("signal-2023-01-01-123456.jpg"));
+        assert_eq!(expect("2023-01-01T12:34:56", "signal"), found("signal-2023-01-01-12-34-56-789.jpg"));
+        assert_eq!(expect("2023-01-01T12:34:56", "telegram"), found("photo_2023-01-01_12-34-56.jpg"));
+        assert_eq!(expect("2023-01-01T12:34:56Z", "pixel"), found("PXL_20230101_123456789.jpg"));
+        assert_eq!(expect("2023-01-01T12:00:00", "screenshot"), found("Screenshot_2023-01-01-12-00-00.png"));
+        assert_eq!(expect("2023-01-01T12:00:00", "screenshot"), found("Screenshot_20230101-120000.png"));
+        assert_eq!(expect("2023-01-01T12:34:56", "date_time"), found("20230101_123456.mp4"));
+        assert_eq!(expect("2023-01-01T12:34:56", "date_time"), found("IMG_20230101_123456_HDR.jpg"));
+    }
+
+    #[test]
+    fn test_no_date() {
+        assert_eq!(None, found("IMG_1234.jpg"));
+        assert_eq!(None, found("IMG-20171301-WA0002.jpg"));
+        assert_eq!(None, found("20230101_253456.mp4"));
+        assert_eq!(None, found("120230101_1234567.mp4"));
+    }
+
+    #[test]
+    fn test_custom() {
+        let custom = FilenamePattern::custom(r"^scan (?P<day>\d\d)\.(?P<month>\d\d)\.(?P<year>\d{4})").unwrap();
+        let dates = FilenameDates::with_custom(vec![custom]);
+        let (ts, name) = dates.date_time("scan 24.12.1999.tiff").unwrap();
+        assert_eq!("1999-12-24T13:00:00", ts.to_string());
+        assert!(name.starts_with("^scan"));
+        // The built-in patterns still apply
+        assert!(dates.date_time("PXL_20230101_123456789.jpg").is_some());
+
+        assert!(FilenamePattern::custom(r"(?P<year>\d{4})(?P<month>\d\d)").is_err());
+        assert!(FilenamePattern::custom(r"(?P<year>\d{4}").is_err());
+
+        // Incomplete times
+        let date = r"(?P<year>\d{4})(?P<month>\d\d)(?P<day>\d\d)";
+        let err = FilenamePattern::custom(&format!("{}_(?P<hour>\\d\\d)", date)).unwrap_err().to_string();
+        assert!(err.contains(date) && err.contains("'hour' needs a group 'minute'"), "{}", err);
+        assert!(FilenamePattern::custom(&format!("{}_(?P<minute>\\d\\d)", date)).is_err());
+        assert!(FilenamePattern::custom(&format!("{}_(?P<hour>\\d\\d)(?P<second>\\d\\d)", date)).is_err());
+        assert!(FilenamePattern::custom(&format!("{}_(?P<hour>\\d\\d)(?P<minute>\\d\\d)", date)).is_ok());
+    }
+}
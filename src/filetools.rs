@@ -1,29 +1,92 @@
+use crate::error::{Error, Result};
+
 use chrono::offset::Utc;
-use chrono::DateTime;
-use std::fs::{self, Metadata};
-use std::io;
+use chrono::{DateTime, NaiveDateTime, Timelike};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
 use std::path::Path;
-use std::time::SystemTime;
 
-pub fn get_time_from_file<P: AsRef<Path>>(p: P) -> io::Result<String> {
-    get_time_from_metadata(fs::metadata(p)?)
+const COMPARE_BUFFER_SIZE: usize = 64 * 1024;
+
+pub fn get_time_from_file<P: AsRef<Path>>(p: P) -> Result<String> {
+    let p = p.as_ref();
+    fs::metadata(p)
+        .and_then(get_time_from_metadata)
+        .map_err(|e| Error::io(p, e))
 }
 
 pub fn get_time_from_metadata(md: Metadata) -> io::Result<String> {
-    // let ct = md.created();
-    let ct = md.modified();
-    if let Ok(creation_time) = ct {
-        Ok(format_time(creation_time))
-    } else {
-        let mt = md.modified()?;
-        Ok(format_time(mt))
+    Ok(get_modified_time(&md)?.format("%Y-%m-%d %T").to_string())
+}
+
+/// The modification time of a file, in UTC.
+pub fn get_modified_time(md: &Metadata) -> io::Result<NaiveDateTime> {
+    let datetime: DateTime<Utc> = DateTime::from(md.modified()?);
+    // Whole seconds, like the timestamps in metadata
+    Ok(datetime.naive_utc().with_nanosecond(0).unwrap_or_else(|| datetime.naive_utc()))
+}
+
+/// Compares two files byte by byte. Files of different sizes are never read.
+pub fn same_content<P1: AsRef<Path>, P2: AsRef<Path>>(p1: P1, p2: P2) -> Result<bool> {
+    let (p1, p2) = (p1.as_ref(), p2.as_ref());
+    let len1 = fs::metadata(p1).map_err(|e| Error::io(p1, e))?.len();
+    let len2 = fs::metadata(p2).map_err(|e| Error::io(p2, e))?.len();
+    if len1 != len2 {
+        return Ok(false);
+    }
+
+    let mut f1 = File::open(p1).map_err(|e| Error::io(p1, e))?;
+    let mut f2 = File::open(p2).map_err(|e| Error::io(p2, e))?;
+    let mut buf1 = vec![0; COMPARE_BUFFER_SIZE];
+    let mut buf2 = vec![0; COMPARE_BUFFER_SIZE];
+    loop {
+        let n1 = read_fully(&mut f1, &mut buf1).map_err(|e| Error::io(p1, e))?;
+        let n2 = read_fully(&mut f2, &mut buf2).map_err(|e| Error::io(p2, e))?;
+        if n1 != n2 || buf1[..n1] != buf2[..n2] {
+            return Ok(false);
+        }
+        if n1 == 0 {
+            return Ok(true);
+        }
     }
 }
 
-fn format_time(t: SystemTime) -> String {
-    let datetime: DateTime<Utc> = DateTime::from(t);
-    let dt = datetime.format("%Y-%m-%d %T");
-    format!("{}", dt)
+// Like read(), but only returns less than the buffer size at the end of the file
+fn read_fully<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match r.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(total)
 }
 
-// TODO unit tests
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+
+    #[test]
+    fn test_same_content() -> Result<()> {
+        let bd = testtools::get_base_dir();
+        assert!(same_content(bd.clone() + "src/test/gps-date.jpg", bd.clone() + "src/test1a/myimg.jpg")?);
+        assert!(!same_content(bd.clone() + "src/test/gps-date.jpg", bd.clone() + "src/test1b/myimg.jpg")?);
+
+        // Same size, one byte different at the end
+        let dir = testtools::get_target_dir() + "test_same_content";
+        fs::create_dir_all(&dir)?;
+        let mut data = fs::read(bd.clone() + "src/test/gps-date.jpg")?;
+        let changed = dir.clone() + "/changed.jpg";
+        let last = data.len() - 3;
+        data[last] ^= 0xff;
+        fs::write(&changed, &data)?;
+        assert!(!same_content(bd.clone() + "src/test/gps-date.jpg", &changed)?);
+
+        assert!(same_content(bd.clone() + "src/test/gps-date.jpg", dir + "/doesnt-exist.jpg").is_err());
+        Ok(())
+    }
+}
\ No newline at end of file
@@ -0,0 +1,172 @@
+use crate::error::{Error, Result};
+
+use globset::{GlobBuilder, GlobMatcher};
+use std::path::{Component, Path};
+
+/// Directories that NAS systems fill with thumbnails and index files, treated as hidden.
+const SYSTEM_DIRS: [&str; 1] = ["@eaDir"];
+
+/// A shell style pattern: `*` matches any part of a name, `**` any number of directories, `?` one
+/// character, `[...]` one of the characters listed, or `[!...]` one not listed, and `{a,b}` either
+/// of the alternatives. Matching ignores case, as photo file extensions are written both ways.
+///
+/// A pattern without a `/` is matched against the name of each file and directory, e.g. `*.tmp`
+/// or `.thumbnails`. A pattern with a `/` is matched against the whole path below the source
+/// directory, e.g. `DCIM/.Trash` or `**/cache/*.jpg`.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    pattern: String,
+    matcher: GlobMatcher,
+    whole_path: bool
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob> {
+        let trimmed = pattern.trim_start_matches('/').trim_end_matches('/');
+        let invalid = |msg: &str| Error::InvalidInput(format!("Invalid pattern '{}': {}", pattern, msg));
+        if trimmed.is_empty() {
+            return Err(invalid("it is empty"));
+        }
+
+        let matcher = GlobBuilder::new(trimmed)
+            .case_insensitive(true)
+            .literal_separator(true)
+            .backslash_escape(false)
+            .build()
+            .map_err(|e| invalid(&e.kind().to_string()))?
+            .compile_matcher();
+        Ok(Glob { pattern: pattern.to_string(), matcher, whole_path: trimmed.contains('/') })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the pattern matches this path, relative to the source directory.
+    pub fn matches(&self, relative: &Path) -> bool {
+        if self.whole_path {
+            self.matcher.is_match(to_slashes(relative))
+        } else {
+            relative.file_name().is_some_and(|n| self.matcher.is_match(n))
+        }
+    }
+}
+
+/// Why a file or directory is left out of a copy, if it is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filtered {
+    Included,
+    /// Its name starts with a dot, or it's a NAS system directory such as Synology's `@eaDir`
+    Hidden,
+    /// It matches an exclude pattern, or no include pattern
+    Excluded
+}
+
+/// Decides which files and directories of the source tree are copied.
+///
+/// Excluded and hidden directories are not looked into. Include patterns only select files:
+/// directories are searched unless excluded, so that `*.jpg` finds the JPEG files in all of them.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    include_hidden: bool
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String], include_hidden: bool) -> Result<PathFilter> {
+        Ok(PathFilter {
+            include: include.iter().map(|p| Glob::new(p)).collect::<Result<_>>()?,
+            exclude: exclude.iter().map(|p| Glob::new(p)).collect::<Result<_>>()?,
+            include_hidden
+        })
+    }
+
+    /// Whether to look into this directory, given by its path relative to the source directory.
+    pub fn check_dir(&self, relative: &Path) -> Filtered {
+        if !self.include_hidden && is_hidden(relative) {
+            Filtered::Hidden
+        } else if self.exclude.iter().any(|g| g.matches(relative)) {
+            Filtered::Excluded
+        } else {
+            Filtered::Included
+        }
+    }
+
+    /// Whether to copy this file, given by its path relative to the source directory.
+    pub fn check_file(&self, relative: &Path) -> Filtered {
+        match self.check_dir(relative) {
+            Filtered::Included if !self.include.is_empty() && !self.include.iter().any(|g| g.matches(relative)) =>
+                Filtered::Excluded,
+            f => f
+        }
+    }
+}
+
+fn is_hidden(p: &Path) -> bool {
+    p.file_name().map(|n| n.to_string_lossy())
+        .is_some_and(|n| n.starts_with('.') || SYSTEM_DIRS.contains(&n.as_ref()))
+}
+
+fn to_slashes(p: &Path) -> String {
+    let names: Vec<_> = p.components()
+        .filter_map(|c| match c {
+            Component::Normal(n) => Some(n.to_string_lossy()),
+            _ => None
+        })
+        .collect();
+    names.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_matches(pattern: &str, path: &str) -> bool {
+        Glob::new(pattern).unwrap().matches(Path::new(path))
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_matches("*.jpg", "2023/IMG_0001.JPG"));
+        assert!(!glob_matches("*.jpg", "2023/IMG_0001.jpeg"));
+        assert!(glob_matches(".trashed-*", "DCIM/Camera/.trashed-1690000000-IMG.jpg"));
+        assert!(glob_matches("IMG_00?1.*", "IMG_0001.heic"));
+        assert!(glob_matches("IMG_[0-4]*", "IMG_3.jpg"));
+        assert!(!glob_matches("IMG_[!0-4]*", "IMG_3.jpg"));
+        assert!(glob_matches("DCIM/.Trash", "DCIM/.Trash"));
+        assert!(!glob_matches("DCIM/.Trash", "backup/DCIM/.Trash"));
+        assert!(glob_matches("**/.Trash", "backup/DCIM/.Trash"));
+        assert!(glob_matches("**/.Trash", ".Trash"));
+        assert!(glob_matches("/DCIM/**/*.mp4", "DCIM/100APPLE/sub/IMG_0001.MP4"));
+        assert!(glob_matches("DCIM/*", "DCIM/100APPLE"));
+        assert!(!glob_matches("DCIM/*", "DCIM/100APPLE/IMG_0001.JPG"));
+        assert!(glob_matches("a+b (1).png", "a+b (1).png"));
+        assert!(glob_matches("*.{jpg,heic}", "IMG_0001.HEIC"));
+    }
+
+    #[test]
+    fn test_invalid_glob() {
+        assert!(Glob::new("IMG_[0-9.jpg").is_err());
+        assert!(Glob::new("/").is_err());
+        assert!(Glob::new("[]]").is_ok());
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::default();
+        assert_eq!(Filtered::Hidden, filter.check_dir(Path::new("DCIM/.thumbnails")));
+        assert_eq!(Filtered::Hidden, filter.check_dir(Path::new("photos/@eaDir")));
+        assert_eq!(Filtered::Hidden, filter.check_file(Path::new(".Hidden.jpg")));
+        assert_eq!(Filtered::Included, filter.check_file(Path::new("DCIM/IMG_0001.JPG")));
+
+        let filter = PathFilter::new(&["*.jpg".into(), "*.mp4".into()], &["cache".into(), "IMG_9*".into()], true).unwrap();
+        assert_eq!(Filtered::Included, filter.check_dir(Path::new("DCIM/.thumbnails")));
+        assert_eq!(Filtered::Included, filter.check_file(Path::new(".Hidden.jpg")));
+        assert_eq!(Filtered::Excluded, filter.check_dir(Path::new("DCIM/cache")));
+        assert_eq!(Filtered::Included, filter.check_dir(Path::new("DCIM")));
+        assert_eq!(Filtered::Included, filter.check_file(Path::new("DCIM/IMG_0001.JPG")));
+        assert_eq!(Filtered::Excluded, filter.check_file(Path::new("DCIM/IMG_9001.JPG")));
+        assert_eq!(Filtered::Excluded, filter.check_file(Path::new("DCIM/IMG_0001.CR2")));
+    }
+}
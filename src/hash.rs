@@ -0,0 +1,40 @@
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Computes the SHA-256 of the file content, as a lower case hex string.
+pub fn hash_file<P: AsRef<Path>>(p: P) -> io::Result<String> {
+    let mut f = File::open(p)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        match f.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+
+    #[test]
+    fn test_hash_file() {
+        let bd = testtools::get_base_dir();
+        let h1 = hash_file(bd.clone() + "src/test/gps-date.jpg").unwrap();
+        assert_eq!(h1, hash_file(bd.clone() + "src/test1a/myimg.jpg").unwrap());
+        assert_ne!(h1, hash_file(bd + "src/test1b/myimg.jpg").unwrap());
+
+        let abc = testtools::get_target_dir() + "test_hash_abc.txt";
+        std::fs::write(&abc, "abc").unwrap();
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", hash_file(&abc).unwrap());
+    }
+}
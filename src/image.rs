@@ -1,50 +1,134 @@
-use crate::copier::DateResult;
+use crate::copier::{DateResult, DateSource};
+use crate::error::{Error, Result};
+use crate::filename_dates::FilenameDates;
 use crate::filetools;
+use crate::jpeg;
+use crate::png;
+use crate::raw;
+use crate::sniff::{self, FileKind};
 use crate::strings::Strings;
+use crate::timestamp::{self, Timestamp, Zone};
 
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime};
 use log::debug;
 use std::io::BufReader;
 use std::fs::File;
 use std::path::Path;
-use std::process::Command;
-use regex::Regex;
 
 pub struct PhotoHandler {}
 
 impl PhotoHandler {
     // TODO refactor to get_date() as the time cannot always be obtained and we don't need it
-    pub fn get_date_time(p: &Path) -> (DateResult, bool) {
-        let f = File::open(p).unwrap();
-        let mut bufreader = BufReader::new(&f);
-        let exifreader = exif::Reader::new();
-
-        if let Ok(reader) = exifreader.read_from_container(&mut bufreader) {
-            if let Some(v) = PhotoHandler::get_tag(&reader, exif::Tag::GPSTimeStamp) {
-                if let Some(date) = PhotoHandler::get_tag(&reader, exif::Tag::GPSDateStamp) {
-                    let date_time = date + " " + v.as_str();
-
-                    let valid_dt = chrono::NaiveDateTime::parse_from_str(&date_time, "%Y-%m-%d %H:%M:%S");
-                    if valid_dt.is_ok() {
-                        return (DateResult::FromMetadata(Strings::truncate_at('.', date_time)), true);
-                    }
-                }
+    pub fn get_date_time(p: &Path) -> Result<(DateResult, bool)> {
+        PhotoHandler::get_date_time_using(p, FilenameDates::builtin())
+    }
+
+    /// The date and time of the photo, using these patterns for files without date metadata.
+    pub fn get_date_time_using(p: &Path, filename_dates: &FilenameDates) -> Result<(DateResult, bool)> {
+        let f = File::open(p).map_err(|e| Error::io(p, e))?;
+        if let Ok(reader) = raw::read_exif(&mut BufReader::new(&f)) {
+            let original = PhotoHandler::get_local_time(&reader, exif::Tag::DateTimeOriginal, exif::Tag::OffsetTimeOriginal);
+
+            if let Some(utc) = PhotoHandler::get_gps_time(&reader) {
+                return Ok((DateResult::FromMetadata(PhotoHandler::gps_timestamp(utc, original), DateSource::Gps), true));
             }
 
-            if let Some(v) = PhotoHandler::get_tag(&reader, exif::Tag::DateTimeOriginal) {
-                return (DateResult::FromMetadata(v), true);
+            if let Some(ts) = original {
+                return Ok((DateResult::FromMetadata(ts, DateSource::DateTimeOriginal), true));
             }
 
-            if let Some(v) = PhotoHandler::get_tag(&reader, exif::Tag::DateTime) {
-                return (DateResult::FromMetadata(v), true);
+            if let Some(ts) = PhotoHandler::get_local_time(&reader, exif::Tag::DateTime, exif::Tag::OffsetTime) {
+                return Ok((DateResult::FromMetadata(ts, DateSource::DateTime), true));
             }
         }
 
-        if let Some(v) = PhotoHandler::get_whatsapp_filename_date(p) {
-            return (DateResult::Inferred(v), false);
+        if let Some(ts) = png::read_text(p, png::CREATION_TIME).ok().flatten()
+                .and_then(|s| PhotoHandler::parse_png_time(&s)) {
+            return Ok((DateResult::FromMetadata(ts, DateSource::PngCreationTime), false));
+        }
+
+        if let Some((v, _)) = p.file_name().and_then(|n| filename_dates.date_time(&n.to_string_lossy())) {
+            Ok((DateResult::Inferred(v, DateSource::Filename), false))
         } else {
             debug!("No Exif tag found for date, using file date instead.");
-            let md = f.metadata().unwrap();
-            return (DateResult::Inferred(filetools::get_time_from_metadata(md).unwrap()), false);
+            let modified = f.metadata()
+                .and_then(|md| filetools::get_modified_time(&md))
+                .map_err(|e| Error::io(p, e))?;
+            Ok((DateResult::Inferred(Timestamp::utc(modified), DateSource::FileModified), false))
+        }
+    }
+
+    // The PNG specification suggests RFC 1123 dates, e.g. Tue, 14 Mar 2023 09:26:53 +0100, but
+    // programs also write ISO 8601 and EXIF style dates
+    fn parse_png_time(s: &str) -> Option<Timestamp> {
+        let s = s.trim();
+        if let Ok(dt) = DateTime::parse_from_rfc2822(s).or_else(|_| DateTime::parse_from_rfc3339(s)) {
+            return Some(match dt.offset().local_minus_utc() {
+                0 => Timestamp::utc(dt.naive_utc()),
+                _ => Timestamp::with_offset(dt.naive_local(), *dt.offset())
+            });
+        }
+        ["%Y:%m:%d %H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"].iter()
+            .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+            .map(Timestamp::floating)
+    }
+
+    // The GPS date and time, which are in UTC
+    fn get_gps_time(reader: &exif::Exif) -> Option<NaiveDateTime> {
+        let time = PhotoHandler::get_tag(reader, exif::Tag::GPSTimeStamp)?;
+        let date = PhotoHandler::get_tag(reader, exif::Tag::GPSDateStamp)?;
+        let date_time = Strings::truncate_at('.', date + " " + time.as_str());
+        NaiveDateTime::parse_from_str(&date_time, "%Y-%m-%d %H:%M:%S").ok()
+    }
+
+    // A camera date and time, with the offset from the given tag if there is one
+    fn get_local_time(reader: &exif::Exif, tag: exif::Tag, offset_tag: exif::Tag) -> Option<Timestamp> {
+        let ts = Timestamp::parse_floating(&PhotoHandler::get_tag(reader, tag)?)?;
+        match PhotoHandler::get_ascii_tag(reader, offset_tag).and_then(|o| timestamp::parse_offset(&o)) {
+            Some(offset) => Some(Timestamp::with_offset(ts.date_time, offset)),
+            None => Some(ts)
+        }
+    }
+
+    // GPS gives the exact moment in UTC, the camera clock the local time. Without a recorded offset the
+    // difference between the two, rounded to a quarter of an hour, is the offset.
+    fn gps_timestamp(utc: NaiveDateTime, camera: Option<Timestamp>) -> Timestamp {
+        let offset = match camera.map(|c| c.zone) {
+            Some(Zone::Offset(o)) => Some(o),
+            Some(Zone::Floating) => camera.and_then(|c| {
+                let minutes = (c.date_time - utc).num_minutes();
+                let quarters = (minutes as f64 / 15.0).round() as i32;
+                if quarters.abs() <= 14 * 4 {
+                    FixedOffset::east_opt(quarters * 15 * 60)
+                } else {
+                    None
+                }
+            }),
+            _ => None
+        };
+        match offset {
+            Some(o) => Timestamp::with_offset(utc + Duration::seconds(o.local_minus_utc() as i64), o),
+            None => Timestamp::utc(utc)
+        }
+    }
+
+    /// The make and model of the camera, if known.
+    pub fn get_camera(p: &Path) -> (Option<String>, Option<String>) {
+        let exif = File::open(p).ok()
+            .and_then(|f| raw::read_exif(&mut BufReader::new(f)).ok());
+        match exif {
+            Some(exif) => (PhotoHandler::get_ascii_tag(&exif, exif::Tag::Make),
+                PhotoHandler::get_ascii_tag(&exif, exif::Tag::Model)),
+            None => (None, None)
+        }
+    }
+
+    fn get_ascii_tag(reader: &exif::Exif, tag: exif::Tag) -> Option<String> {
+        match &reader.get_field(tag, exif::In::PRIMARY)?.value {
+            exif::Value::Ascii(v) if !v.is_empty() => {
+                Some(String::from_utf8_lossy(&v[0]).trim().to_string()).filter(|s| !s.is_empty())
+            },
+            _ => None
         }
     }
 
@@ -54,43 +138,26 @@ impl PhotoHandler {
     }
     */
 
-    fn get_whatsapp_filename_date(path: &Path) -> Option<String> {
-        let p = Regex::new(r"IMG-(\d{8})-WA\d{4}.jpg").unwrap(); // TODO make constant
-        let f = &path.file_name().unwrap().to_string_lossy();
-        let res = p.captures(f);
-        if let Some(x) = res {
-            let ds = x[1].to_string();
-            debug!("Found a whatsapp file date! {:?}", ds);
-            // TODO remove the dummy time of 1300 hrs
-            let d = format!("{}-{}-{} 13:00:00", &ds[0..4], &ds[4..6], &ds[6..8]);
-            return Some(d)
-        }
-        None
-    }
-
     fn get_tag(reader: &exif::Exif, tag: exif::Tag) -> Option<String> {
-        let field = reader.get_field(tag, exif::In::PRIMARY);
-        if let None = field {
-            None
-        } else {
-            let val = &field.unwrap().value;
-            let t = *(&field.unwrap().tag);
-            debug!("Value of tag {} is {}", tag, val.display_as(t));
-            Some(format!("{}", val.display_as(t)))
-        }
+        let field = reader.get_field(tag, exif::In::PRIMARY)?;
+        let val = field.value.display_as(field.tag);
+        debug!("Value of tag {} is {}", tag, val);
+        Some(format!("{}", val))
     }
 
-    pub fn set_exif_date_time(file_name: & str, time_stamp: &str, create_exif: bool) {
-        let ts = format!("-ts{}:{}:{}-{}:{}:{}",
-            &time_stamp[0..4], &time_stamp[5..7], &time_stamp[8..10],
-            &time_stamp[11..13], &time_stamp[14..16], &time_stamp[17..19]);
-        let mut jh = Command::new("jhead");
-        let mut cmd = jh.arg(ts);
+    /// Whether set_exif_date_time() can write to this file, i.e. whether it is a JPEG file. When
+    /// the file can't be read the extension decides.
+    pub fn can_write_exif(p: &Path) -> bool {
+        let kind = sniff::sniff_file(p).ok().flatten().or_else(|| FileKind::from_path(p));
+        kind == Some(FileKind::Jpeg)
+    }
 
-        if create_exif {
-            cmd = cmd.arg("-mkexif");
+    pub fn set_exif_date_time<P: AsRef<Path>>(p: P, dt: &NaiveDateTime) -> Result<()> {
+        let p = p.as_ref();
+        if !PhotoHandler::can_write_exif(p) {
+            return Err(Error::unsupported(p, "cannot write EXIF data to this kind of file"));
         }
-        cmd.arg(file_name).output().expect("Failed to execute jhead, is it installed?");
+        jpeg::set_exif_date_time(p, dt)
     }
 }
 
@@ -100,15 +167,34 @@ mod tests {
     use crate::testtools;
     use chrono::DateTime;
     use chrono::offset::Utc;
-    use std::io;
     use std::fs;
+    use std::io;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
 
     #[test]
     fn test_photo_gps_date_time() {
         let s = String::from(testtools::get_base_dir() + "src/test/gps-date.jpg");
         let p = Path::new(&s);
-        assert_eq!((DateResult::FromMetadata(String::from("2019-04-27 14:08:01")), true),
-            PhotoHandler::get_date_time(&p));
+        // GPS says 14:08:01 UTC, the camera clock 15:08:02, so the photo was taken at UTC+1
+        let expected = Timestamp::with_offset(dt("2019-04-27 15:08:01"), FixedOffset::east_opt(3600).unwrap());
+        assert_eq!((DateResult::FromMetadata(expected, DateSource::Gps), true),
+            PhotoHandler::get_date_time(&p).unwrap());
+    }
+
+    #[test]
+    fn test_gps_timestamp() {
+        let utc = dt("2019-04-27 23:30:00");
+        let plus2 = FixedOffset::east_opt(7200).unwrap();
+        assert_eq!(Timestamp::utc(utc), PhotoHandler::gps_timestamp(utc, None));
+        assert_eq!(Timestamp::with_offset(dt("2019-04-28 01:30:00"), plus2),
+            PhotoHandler::gps_timestamp(utc, Some(Timestamp::floating(dt("2019-04-28 01:29:57")))));
+        assert_eq!(Timestamp::with_offset(dt("2019-04-28 01:30:00"), plus2),
+            PhotoHandler::gps_timestamp(utc, Some(Timestamp::with_offset(dt("2019-04-28 01:35:00"), plus2))));
+        // A camera clock that is way off says nothing about the time zone
+        assert_eq!(Timestamp::utc(utc), PhotoHandler::gps_timestamp(utc, Some(Timestamp::floating(dt("2019-05-28 01:30:00")))));
     }
 
     #[test]
@@ -116,9 +202,9 @@ mod tests {
         let filename = testtools::get_base_dir() + "src/test/NO_METADATA.JPEG";
         let md = fs::metadata(&filename)?;
         let created: DateTime<Utc> = DateTime::from(md.created()?);
-        let expected = format!("{}", created.format("%Y-%m-%d %T"));
-        assert_eq!((DateResult::Inferred(expected), false),
-            PhotoHandler::get_date_time(Path::new(&filename)));
+        let expected = Timestamp::utc(dt(&created.format("%Y-%m-%d %T").to_string()));
+        assert_eq!((DateResult::Inferred(expected, DateSource::FileModified), false),
+            PhotoHandler::get_date_time(Path::new(&filename)).unwrap());
         Ok(())
     }
 
@@ -126,8 +212,70 @@ mod tests {
     fn test_raw_photo_timestamp() -> io::Result<()> {
         let filename = testtools::get_base_dir() + "src/test/raw/samsung_raw_photo.dng";
         let p = Path::new(&filename);
-        assert_eq!(DateResult::FromMetadata(String::from("2024-05-10 22:57:40")),
-            PhotoHandler::get_date_time(&p).0);
+        assert_eq!(DateResult::FromMetadata(Timestamp::floating(dt("2024-05-10 22:57:40")), DateSource::DateTimeOriginal),
+            PhotoHandler::get_date_time(&p).unwrap().0);
         Ok(())
     }
+
+    #[test]
+    fn test_raw_formats() {
+        let filename = testtools::get_base_dir() + "src/testraw/canon.cr3";
+        let expected = Timestamp::with_offset(dt("2021-06-15 10:00:02"), FixedOffset::east_opt(7200).unwrap());
+        assert_eq!((DateResult::FromMetadata(expected, DateSource::DateTimeOriginal), true),
+            PhotoHandler::get_date_time(Path::new(&filename)).unwrap());
+        let filename = testtools::get_base_dir() + "src/testraw/fujifilm.raf";
+        assert_eq!((Some("FUJIFILM".to_string()), Some("X-T4".to_string())),
+            PhotoHandler::get_camera(Path::new(&filename)));
+    }
+
+    #[test]
+    fn test_image_formats() {
+        let date_time = |name: &str| {
+            let filename = testtools::get_base_dir() + "src/testimage/" + name;
+            PhotoHandler::get_date_time(Path::new(&filename)).unwrap()
+        };
+        let exif = |s: &str| (DateResult::FromMetadata(Timestamp::floating(dt(s)), DateSource::DateTimeOriginal), true);
+        assert_eq!(exif("2020-01-02 03:04:05"), date_time("photo.png"));
+        assert_eq!(exif("2020-02-03 04:05:06"), date_time("sticker.webp"));
+        assert_eq!(exif("2020-03-04 05:06:07"), date_time("scan.tiff"));
+        assert_eq!(exif("2020-04-05 06:07:08"), date_time("export.avif"));
+        let expected = Timestamp::with_offset(dt("2023-03-14 09:26:53"), FixedOffset::east_opt(3600).unwrap());
+        assert_eq!((DateResult::FromMetadata(expected, DateSource::PngCreationTime), false), date_time("screenshot.png"));
+        assert!(matches!(date_time("animation.gif"), (DateResult::Inferred(_, DateSource::FileModified), false)));
+    }
+
+    #[test]
+    fn test_parse_png_time() {
+        assert_eq!(Some(Timestamp::utc(dt("2023-03-14 09:26:53"))), PhotoHandler::parse_png_time("Tue, 14 Mar 2023 09:26:53 GMT"));
+        assert_eq!(Some(Timestamp::floating(dt("2023-03-14 09:26:53"))), PhotoHandler::parse_png_time("2023:03:14 09:26:53"));
+        assert_eq!(Some(Timestamp::with_offset(dt("2023-03-14 09:26:53"), FixedOffset::west_opt(7 * 3600).unwrap())),
+            PhotoHandler::parse_png_time("2023-03-14T09:26:53-07:00"));
+        assert_eq!(None, PhotoHandler::parse_png_time("last Tuesday"));
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let filename = testtools::get_base_dir() + "src/test/doesnt-exist.jpg";
+        match PhotoHandler::get_date_time(Path::new(&filename)) {
+            Err(Error::Io { path: Some(p), .. }) => assert_eq!(Path::new(&filename), p),
+            r => panic!("Unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_get_camera() {
+        let filename = testtools::get_base_dir() + "src/test/gps-date.jpg";
+        assert_eq!((Some("Sony".to_string()), Some("H3113".to_string())),
+            PhotoHandler::get_camera(Path::new(&filename)));
+        let filename = testtools::get_base_dir() + "src/test/NO_EXIF.JPG";
+        assert_eq!((None, None), PhotoHandler::get_camera(Path::new(&filename)));
+    }
+
+    #[test]
+    fn test_set_exif_date_time_unsupported() {
+        let filename = testtools::get_base_dir() + "src/test/heic/image1.heic";
+        assert!(!PhotoHandler::can_write_exif(Path::new(&filename)));
+        assert!(PhotoHandler::set_exif_date_time(&filename, &dt("2001-12-29 07:00:01")).is_err());
+        assert!(PhotoHandler::can_write_exif(Path::new("/some/dir/IMG_0001.JPG")));
+    }
 }
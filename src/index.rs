@@ -0,0 +1,308 @@
+use crate::hash;
+
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the index file, kept in the root of the destination directory.
+pub const INDEX_FILE_NAME: &str = ".phototools-index";
+
+/// Remembers which content was imported into a destination directory and where it was stored.
+///
+/// The index file has one line per imported file: the SHA-256 of the content, a tab and the
+/// path relative to the destination root. When the stored file differs from the imported
+/// content, for example because EXIF dates were added, a tab and the SHA-256 of the stored file
+/// follow. New entries are appended, a later line for the same hash replaces an earlier one.
+pub struct ImportIndex {
+    root: PathBuf,
+    entries: HashMap<String, Entry>,
+    file: Option<File>,
+    read_only: bool,
+    // Hashes added while read only, their files don't exist
+    unsaved: HashSet<String>,
+    // Hashes whose file was found to still have that content
+    verified: HashSet<String>
+}
+
+struct Entry {
+    path: PathBuf,
+    // Hash of the stored file, when it differs from the imported content
+    stored_hash: Option<String>
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<(String, Entry)> {
+        let (hash, rest) = line.split_once('\t')?;
+        let (path, stored_hash) = match rest.rsplit_once('\t') {
+            Some((path, h)) if is_hash(h) => (path, Some(h.to_string())),
+            _ => (rest, None)
+        };
+        if hash.is_empty() || path.is_empty() {
+            return None;
+        }
+        Some((hash.to_string(), Entry { path: PathBuf::from(path), stored_hash }))
+    }
+}
+
+fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl ImportIndex {
+    /// Loads the index of the destination root, if there is one. When a destination that was
+    /// filled without an index has none yet, the files already there are hashed into a new one.
+    pub fn open(root: &Path) -> io::Result<ImportIndex> {
+        ImportIndex::load(root, false)
+    }
+
+    /// Loads the index like open(), but additions are only kept in memory.
+    pub fn open_read_only(root: &Path) -> io::Result<ImportIndex> {
+        ImportIndex::load(root, true)
+    }
+
+    fn load(root: &Path, read_only: bool) -> io::Result<ImportIndex> {
+        let mut index = ImportIndex {
+            root: root.to_path_buf(),
+            entries: HashMap::new(),
+            file: None,
+            read_only,
+            unsaved: HashSet::new(),
+            verified: HashSet::new()
+        };
+        match File::open(root.join(INDEX_FILE_NAME)) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    match Entry::parse(&line) {
+                        Some((hash, entry)) => {
+                            index.entries.insert(hash, entry);
+                        },
+                        None => warn!("Ignoring invalid line in import index: {}", line)
+                    }
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound && root.is_dir() => index.rebuild()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e)
+        }
+        debug!("Loaded {} entries from the import index in {:?}", index.entries.len(), root);
+        Ok(index)
+    }
+
+    // Adds the files already in the destination. Hidden files, such as the index itself and
+    // partial copies, are left out.
+    fn rebuild(&mut self) -> io::Result<()> {
+        info!("No import index in {} yet, hashing the files already there", self.root.to_string_lossy());
+        let mut files = Vec::new();
+        collect_files(&self.root, &mut files)?;
+        for p in files {
+            match hash::hash_file(&p) {
+                Ok(h) => {
+                    self.add(&h, &p, &h)?;
+                    self.verified.insert(h);
+                },
+                Err(e) => warn!("Unable to add {} to the import index: {}", p.to_string_lossy(), e)
+            }
+        }
+        Ok(())
+    }
+
+    /// Where content with this hash was stored, if it was imported before and is still there.
+    /// The stored file is hashed again, the first time it's looked up: an entry whose file was
+    /// removed, edited or replaced since is dropped.
+    pub fn lookup(&mut self, hash: &str) -> Option<PathBuf> {
+        let (stored, expected) = self.get(hash)?;
+        let Some(expected) = expected else {
+            return Some(stored);
+        };
+        let still_there = ImportIndex::has_hash(&stored, &expected);
+        self.checked(hash, &stored, still_there);
+        still_there.then_some(stored)
+    }
+
+    /// Where content with this hash was stored, without checking the file. The second value is
+    /// the hash the stored file should have, when it still needs checking with has_hash().
+    pub fn get(&self, hash: &str) -> Option<(PathBuf, Option<String>)> {
+        let entry = self.entries.get(hash)?;
+        let stored = self.root.join(&entry.path);
+        if self.unsaved.contains(hash) || self.verified.contains(hash) {
+            return Some((stored, None));
+        }
+        Some((stored, Some(entry.stored_hash.clone().unwrap_or_else(|| hash.to_string()))))
+    }
+
+    /// Whether the stored file has the expected hash.
+    pub fn has_hash(stored: &Path, expected: &str) -> bool {
+        match hash::hash_file(stored) {
+            Ok(h) => h == expected,
+            Err(e) => {
+                debug!("Unable to hash {:?}: {}", stored, e);
+                false
+            }
+        }
+    }
+
+    /// Keeps the outcome of checking a stored file found with get(). An entry whose file no longer
+    /// has its content is dropped.
+    pub fn checked(&mut self, hash: &str, stored: &Path, still_there: bool) {
+        if self.entries.get(hash).map(|e| self.root.join(&e.path)).as_deref() != Some(stored) {
+            // Replaced by a newer entry meanwhile
+            return;
+        }
+        if still_there {
+            self.verified.insert(hash.to_string());
+        } else {
+            debug!("Dropping stale import index entry {}: {:?} no longer has that content", hash, stored);
+            self.entries.remove(hash);
+        }
+    }
+
+    /// Whether there is an entry for this hash, checked or not.
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Records that content with this hash is stored at the given path, which should be inside
+    /// the destination root. The stored file has the stored hash, which is the same as the hash
+    /// unless the file was changed while importing it.
+    pub fn add(&mut self, hash: &str, stored: &Path, stored_hash: &str) -> io::Result<()> {
+        let rel = stored.strip_prefix(&self.root).unwrap_or(stored).to_path_buf();
+        let stored_hash = (stored_hash != hash).then(|| stored_hash.to_string());
+        if self.entries.get(hash).is_some_and(|e| e.path == rel && e.stored_hash == stored_hash) {
+            return Ok(());
+        }
+        self.verified.remove(hash);
+
+        if self.read_only {
+            self.unsaved.insert(hash.to_string());
+        } else if self.file.is_none() {
+            fs::create_dir_all(&self.root)?;
+            self.file = Some(OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.root.join(INDEX_FILE_NAME))?);
+        }
+        if let Some(f) = &mut self.file {
+            match &stored_hash {
+                Some(h) => writeln!(f, "{}\t{}\t{}", hash, rel.to_string_lossy(), h)?,
+                None => writeln!(f, "{}\t{}", hash, rel.to_string_lossy())?
+            }
+        }
+        self.entries.insert(hash.to_string(), Entry { path: rel, stored_hash });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+
+    #[test]
+    fn test_index() -> io::Result<()> {
+        let root = PathBuf::from(testtools::get_target_dir() + "test_index");
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+
+        let mut index = ImportIndex::open(&root)?;
+        assert!(index.is_empty());
+        assert!(!root.exists(), "Opening the index should not create anything");
+
+        let stored = root.join("2019/2019-04-27/a.jpg");
+        fs::create_dir_all(stored.parent().unwrap())?;
+        fs::write(&stored, b"a")?;
+        let a = hash::hash_file(&stored)?;
+        index.add(&a, &stored, &a)?;
+        index.add("def", &root.join("2019/2019-04-27/gone.jpg"), "def")?;
+        assert_eq!(Some(stored.clone()), index.lookup(&a));
+        assert_eq!(None, index.lookup("def"), "File doesn't exist, so not found");
+        assert_eq!(None, index.lookup("xyz"));
+
+        // Reload from disk
+        let mut index = ImportIndex::open(&root)?;
+        assert_eq!(2, index.len());
+        assert_eq!(Some(stored.clone()), index.lookup(&a));
+        assert_eq!(format!("{}\t2019/2019-04-27/a.jpg\ndef\t2019/2019-04-27/gone.jpg\n", a),
+            fs::read_to_string(root.join(INDEX_FILE_NAME))?);
+
+        // A file that was edited since no longer counts as imported
+        fs::write(&stored, b"edited")?;
+        let mut index = ImportIndex::open(&root)?;
+        assert_eq!(None, index.lookup(&a));
+        assert_eq!(1, index.len(), "The stale entry is dropped");
+        fs::write(&stored, b"a")?;
+
+        // A read only index remembers additions without writing them
+        let mut index = ImportIndex::open_read_only(&root)?;
+        index.add("ghi", &root.join("2020/2020-01-01/new.jpg"), "ghi")?;
+        assert_eq!(Some(root.join("2020/2020-01-01/new.jpg")), index.lookup("ghi"));
+        assert_eq!(2, ImportIndex::open(&root)?.len());
+
+        // A file that was changed while importing it is checked against its own hash
+        let changed = root.join("2019/2019-04-27/changed.jpg");
+        fs::write(&changed, b"a with exif")?;
+        let changed_hash = hash::hash_file(&changed)?;
+        ImportIndex::open(&root)?.add("jkl", &changed, &changed_hash)?;
+        let mut index = ImportIndex::open(&root)?;
+        assert_eq!(Some(changed.clone()), index.lookup("jkl"));
+        assert!(fs::read_to_string(root.join(INDEX_FILE_NAME))?
+            .ends_with(&format!("jkl\t2019/2019-04-27/changed.jpg\t{}\n", changed_hash)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild() -> io::Result<()> {
+        let root = PathBuf::from(testtools::get_target_dir() + "test_index_rebuild");
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+        let stored = root.join("2019/2019-04-27/a.jpg");
+        fs::create_dir_all(stored.parent().unwrap())?;
+        fs::write(&stored, b"a")?;
+        fs::write(root.join("2019/2019-04-27/.a.jpg.phototools-partial"), b"partial")?;
+        let a = hash::hash_file(&stored)?;
+
+        // A read only index hashes the files, but doesn't write them
+        let mut index = ImportIndex::open_read_only(&root)?;
+        assert_eq!(1, index.len());
+        assert_eq!(Some(stored.clone()), index.lookup(&a));
+        assert!(!root.join(INDEX_FILE_NAME).exists());
+
+        let mut index = ImportIndex::open(&root)?;
+        assert_eq!(Some(stored.clone()), index.lookup(&a));
+        assert_eq!(format!("{}\t2019/2019-04-27/a.jpg\n", a), fs::read_to_string(root.join(INDEX_FILE_NAME))?);
+
+        // Once there is an index, new files are only added by imports
+        fs::write(root.join("2019/2019-04-27/b.jpg"), b"b")?;
+        assert_eq!(1, ImportIndex::open(&root)?.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+use crate::error::{Error, Result};
+
+use chrono::NaiveDateTime;
+use log::debug;
+use std::convert::TryInto;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const MARKER_APP0: u8 = 0xe0;
+const MARKER_APP1: u8 = 0xe1;
+const MARKER_SOS: u8 = 0xda;
+const MARKER_EOI: u8 = 0xd9;
+
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_DATE_TIME_DIGITIZED: u16 = 0x9004;
+const TYPE_ASCII: u16 = 2;
+const TYPE_LONG: u16 = 4;
+const TRUNCATED: &str = "truncated TIFF structure";
+
+/// The location of the segments in a JPEG file that matter when writing EXIF data.
+#[derive(Debug, PartialEq)]
+struct Segments {
+    // Range of the complete APP1 segment holding the EXIF data, including marker and length
+    exif: Option<Range<usize>>,
+    // Where a new APP1 segment should go: after SOI, or after the JFIF APP0 segment if there is one
+    insert_at: usize
+}
+
+/// Sets DateTime, DateTimeOriginal and DateTimeDigitized in the EXIF data of a JPEG file.
+/// Other EXIF fields are kept where they are. If the file has no EXIF data yet it is created.
+pub fn set_exif_date_time<P: AsRef<Path>>(p: P, dt: &NaiveDateTime) -> Result<()> {
+    let p = p.as_ref();
+    let data = fs::read(p).map_err(|e| Error::io(p, e))?;
+    let permissions = fs::metadata(p).map_err(|e| Error::io(p, e))?.permissions();
+    let segments = find_segments(p, &data)?;
+
+    let existing_tiff = segments.exif.as_ref()
+        .map(|r| data[r.start + 4 + EXIF_HEADER.len()..r.end].to_vec());
+    let tiff = build_tiff(existing_tiff, dt)
+        .map_err(|e| Error::metadata(p, e))?;
+
+    let segment_len = 2 + EXIF_HEADER.len() + tiff.len();
+    if segment_len > u16::MAX as usize {
+        return Err(Error::metadata(p, "EXIF data too large for a JPEG APP1 segment"));
+    }
+
+    let (before, after) = match &segments.exif {
+        Some(r) => (&data[..r.start], &data[r.end..]),
+        None => (&data[..segments.insert_at], &data[segments.insert_at..])
+    };
+    let mut out = Vec::with_capacity(data.len() + segment_len);
+    out.extend_from_slice(before);
+    out.extend_from_slice(&[0xff, MARKER_APP1]);
+    out.extend_from_slice(&(segment_len as u16).to_be_bytes());
+    out.extend_from_slice(EXIF_HEADER);
+    out.extend_from_slice(&tiff);
+    out.extend_from_slice(after);
+
+    // Write next to the original and rename so that a failure never leaves a half written file.
+    // The new file gets the permissions of the original rather than the defaults.
+    let mut tmp_name = p.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".exif-tmp");
+    let tmp = p.with_file_name(tmp_name);
+    let res = fs::write(&tmp, &out)
+        .and_then(|_| fs::set_permissions(&tmp, permissions))
+        .and_then(|_| fs::rename(&tmp, p));
+    if let Err(e) = res {
+        let _ = fs::remove_file(&tmp);
+        return Err(Error::io(p, e));
+    }
+    debug!("Wrote EXIF date {} to {:?}", dt, p);
+    Ok(())
+}
+
+// Adds the dates without moving any of the existing data: IFD0 and the Exif IFD are copied to the
+// end with the date entries set, and the header is pointed at the new IFD0. Maker notes and other
+// fields that refer to data by its offset stay valid, even those this doesn't understand.
+fn build_tiff(existing: Option<Vec<u8>>, dt: &NaiveDateTime) -> std::result::Result<Vec<u8>, &'static str> {
+    let mut tiff = match existing {
+        Some(data) => Tiff::parse(data)?,
+        None => Tiff::empty()
+    };
+    let ifd0_offset = tiff.u32_at(4).ok_or(TRUNCATED)?;
+    let (mut ifd0, ifd1_offset) = tiff.read_ifd(ifd0_offset)?;
+    let (mut exif_ifd, exif_next) = match ifd0.iter().find(|e| e.tag == TAG_EXIF_IFD) {
+        Some(e) => tiff.read_ifd(tiff.u32_value(e))?,
+        None => (Vec::new(), 0)
+    };
+
+    let date = format!("{}\0", dt.format("%Y:%m:%d %H:%M:%S")).into_bytes();
+    let mut date_entry = |tag: u16| {
+        let offset = tiff.append(&date);
+        IfdEntry { tag, field_type: TYPE_ASCII, count: date.len() as u32, value: tiff.u32_bytes(offset) }
+    };
+    let date_time = date_entry(TAG_DATE_TIME);
+    let original = date_entry(TAG_DATE_TIME_ORIGINAL);
+    let digitized = date_entry(TAG_DATE_TIME_DIGITIZED);
+    set_entry(&mut exif_ifd, original);
+    set_entry(&mut exif_ifd, digitized);
+    let exif_offset = tiff.write_ifd(exif_ifd, exif_next);
+
+    set_entry(&mut ifd0, date_time);
+    set_entry(&mut ifd0, IfdEntry { tag: TAG_EXIF_IFD, field_type: TYPE_LONG, count: 1, value: tiff.u32_bytes(exif_offset) });
+    let new_ifd0_offset = tiff.write_ifd(ifd0, ifd1_offset);
+    let header_offset = tiff.u32_bytes(new_ifd0_offset);
+    tiff.data[4..8].copy_from_slice(&header_offset);
+    Ok(tiff.data)
+}
+
+/// A TIFF structure as found in the EXIF segment, with its byte order.
+struct Tiff {
+    data: Vec<u8>,
+    little_endian: bool
+}
+
+/// An IFD entry as stored: the value, or the offset of the value when it doesn't fit in four bytes.
+#[derive(Clone, Debug, PartialEq)]
+struct IfdEntry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    value: [u8; 4]
+}
+
+impl Tiff {
+    fn parse(data: Vec<u8>) -> std::result::Result<Tiff, &'static str> {
+        let little_endian = match data.get(..4) {
+            Some(b"II*\0") => true,
+            Some(b"MM\0*") => false,
+            _ => return Err("invalid TIFF header")
+        };
+        Ok(Tiff { data, little_endian })
+    }
+
+    // Just a header and an empty IFD0
+    fn empty() -> Tiff {
+        let mut data = b"MM\0*".to_vec();
+        data.extend_from_slice(&8u32.to_be_bytes());
+        data.extend_from_slice(&[0; 6]);
+        Tiff { data, little_endian: false }
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let b: [u8; 2] = self.data.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.little_endian { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let b: [u8; 4] = self.data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(self.u32_from(b))
+    }
+
+    fn u32_from(&self, b: [u8; 4]) -> u32 {
+        if self.little_endian { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+    }
+
+    fn u32_value(&self, entry: &IfdEntry) -> u32 {
+        self.u32_from(entry.value)
+    }
+
+    fn u16_bytes(&self, v: u16) -> [u8; 2] {
+        if self.little_endian { v.to_le_bytes() } else { v.to_be_bytes() }
+    }
+
+    fn u32_bytes(&self, v: u32) -> [u8; 4] {
+        if self.little_endian { v.to_le_bytes() } else { v.to_be_bytes() }
+    }
+
+    // The entries of the IFD and the offset of the next one
+    fn read_ifd(&self, offset: u32) -> std::result::Result<(Vec<IfdEntry>, u32), &'static str> {
+        let offset = offset as usize;
+        let count = self.u16_at(offset).ok_or(TRUNCATED)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let pos = offset + 2 + i * 12;
+            entries.push(IfdEntry {
+                tag: self.u16_at(pos).ok_or(TRUNCATED)?,
+                field_type: self.u16_at(pos + 2).ok_or(TRUNCATED)?,
+                count: self.u32_at(pos + 4).ok_or(TRUNCATED)?,
+                value: self.data.get(pos + 8..pos + 12).ok_or(TRUNCATED)?.try_into().unwrap()
+            });
+        }
+        let next = self.u32_at(offset + 2 + count * 12).ok_or(TRUNCATED)?;
+        Ok((entries, next))
+    }
+
+    // Adds the bytes at the end, at a word boundary as TIFF requires, and returns their offset
+    fn append(&mut self, bytes: &[u8]) -> u32 {
+        if self.data.len() % 2 == 1 {
+            self.data.push(0);
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(bytes);
+        offset
+    }
+
+    fn write_ifd(&mut self, mut entries: Vec<IfdEntry>, next: u32) -> u32 {
+        entries.sort_by_key(|e| e.tag);
+        let mut ifd = self.u16_bytes(entries.len() as u16).to_vec();
+        for e in &entries {
+            ifd.extend_from_slice(&self.u16_bytes(e.tag));
+            ifd.extend_from_slice(&self.u16_bytes(e.field_type));
+            ifd.extend_from_slice(&self.u32_bytes(e.count));
+            ifd.extend_from_slice(&e.value);
+        }
+        ifd.extend_from_slice(&self.u32_bytes(next));
+        self.append(&ifd)
+    }
+}
+
+fn set_entry(entries: &mut Vec<IfdEntry>, entry: IfdEntry) {
+    entries.retain(|e| e.tag != entry.tag);
+    entries.push(entry);
+}
+
+fn find_segments(p: &Path, data: &[u8]) -> Result<Segments> {
+    if data.len() < 4 || data[0] != 0xff || data[1] != 0xd8 {
+        return Err(Error::unsupported(p, "not a JPEG file"));
+    }
+
+    let mut segments = Segments { exif: None, insert_at: 2 };
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xff {
+            return Err(Error::metadata(p, "corrupt JPEG segment structure"));
+        }
+        let marker = data[pos + 1];
+        if marker == 0xff {
+            // Fill byte
+            pos += 1;
+            continue;
+        }
+        if marker == MARKER_SOS || marker == MARKER_EOI {
+            break;
+        }
+
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return Err(Error::metadata(p, "truncated JPEG segment"));
+        }
+
+        if marker == MARKER_APP0 && pos == 2 {
+            segments.insert_at = end;
+        }
+        if marker == MARKER_APP1 && segments.exif.is_none() && data[pos + 4..end].starts_with(EXIF_HEADER) {
+            segments.exif = Some(pos..end);
+        }
+        pos = end;
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+    use exif::{In, Tag, Value};
+    use std::fs::File;
+    use std::io::BufReader;
+
+    fn copy_to_target(src: &str, name: &str) -> String {
+        let dir = testtools::get_target_dir() + "test_jpeg";
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir + "/" + name;
+        fs::copy(testtools::get_base_dir() + src, &target).unwrap();
+        target
+    }
+
+    fn read_exif(p: &str) -> exif::Exif {
+        let f = File::open(p).unwrap();
+        exif::Reader::new().read_from_container(&mut BufReader::new(f)).unwrap()
+    }
+
+    fn tag_value(exif: &exif::Exif, tag: Tag) -> String {
+        let f = exif.get_field(tag, In::PRIMARY).unwrap();
+        f.display_value().to_string()
+    }
+
+    fn date_time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_create_exif() {
+        let p = copy_to_target("src/test/NO_EXIF.JPG", "create.jpg");
+        assert!(exif::Reader::new().read_from_container(&mut BufReader::new(File::open(&p).unwrap())).is_err());
+
+        set_exif_date_time(&p, &date_time("2017-07-01 13:00:00")).unwrap();
+
+        let exif = read_exif(&p);
+        assert_eq!("2017-07-01 13:00:00", tag_value(&exif, Tag::DateTimeOriginal));
+        assert_eq!("2017-07-01 13:00:00", tag_value(&exif, Tag::DateTimeDigitized));
+        assert_eq!("2017-07-01 13:00:00", tag_value(&exif, Tag::DateTime));
+
+        // The new segment goes after the JFIF header
+        let data = fs::read(&p).unwrap();
+        assert_eq!(Some(20), find_segments(Path::new(&p), &data).unwrap().exif.map(|r| r.start));
+        assert_eq!(&[0xff, MARKER_APP0], &data[2..4]);
+        assert_eq!(&[0xff, MARKER_APP1], &data[20..22]);
+    }
+
+    #[test]
+    fn test_update_exif() {
+        let p = copy_to_target("src/test/gps-date.jpg", "update.jpg");
+        let before = read_exif(&p);
+
+        set_exif_date_time(&p, &date_time("2001-12-29 07:00:01")).unwrap();
+
+        let after = read_exif(&p);
+        assert_eq!("2001-12-29 07:00:01", tag_value(&after, Tag::DateTimeOriginal));
+        assert_eq!(tag_value(&before, Tag::GPSDateStamp), tag_value(&after, Tag::GPSDateStamp));
+        assert_eq!(tag_value(&before, Tag::GPSTimeStamp), tag_value(&after, Tag::GPSTimeStamp));
+
+        // Only the EXIF segment changed, the image data is the same
+        let org = fs::read(testtools::get_base_dir() + "src/test/gps-date.jpg").unwrap();
+        let new = fs::read(&p).unwrap();
+        let org_exif = find_segments(Path::new("org"), &org).unwrap().exif.unwrap();
+        let new_exif = find_segments(Path::new(&p), &new).unwrap().exif.unwrap();
+        assert_eq!(&org[org_exif.end..], &new[new_exif.end..]);
+    }
+
+    // The TIFF data of a JPEG file
+    fn tiff_of(p: &str) -> Vec<u8> {
+        let data = fs::read(p).unwrap();
+        let exif = find_segments(Path::new(p), &data).unwrap().exif.unwrap();
+        data[exif.start + 4 + EXIF_HEADER.len()..exif.end].to_vec()
+    }
+
+    #[test]
+    fn test_existing_data_not_moved() {
+        let p = copy_to_target("src/test/NO_METADATA.JPEG", "not-moved.jpg");
+        let before = tiff_of(&p);
+        set_exif_date_time(&p, &date_time("2017-07-01 13:00:00")).unwrap();
+
+        let after = tiff_of(&p);
+        assert_eq!(before[..4], after[..4]);
+        assert_eq!(before[8..], after[8..before.len()]);
+        assert_eq!("2017-07-01 13:00:00", tag_value(&read_exif(&p), Tag::DateTimeOriginal));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions_kept() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let p = copy_to_target("src/test/NO_METADATA.JPEG", "permissions.jpg");
+        fs::set_permissions(&p, fs::Permissions::from_mode(0o640)).unwrap();
+        set_exif_date_time(&p, &date_time("2017-07-01 13:00:00")).unwrap();
+        assert_eq!(0o640, fs::metadata(&p).unwrap().permissions().mode() & 0o777);
+        assert_eq!("2017-07-01 13:00:00", tag_value(&read_exif(&p), Tag::DateTimeOriginal));
+    }
+
+    #[test]
+    fn test_maker_note_kept() {
+        // IFD0 with Make and the Exif IFD, which has a maker note that, like Canon's, refers to
+        // its data by the offset from the start of the TIFF header
+        let mut tiff = b"MM\0*\0\0\0\x08".to_vec();
+        tiff.extend_from_slice(b"\0\x02");
+        tiff.extend_from_slice(b"\x01\x0f\0\x02\0\0\0\x06\0\0\0\x26");
+        tiff.extend_from_slice(b"\x87\x69\0\x04\0\0\0\x01\0\0\0\x2c");
+        tiff.extend_from_slice(b"\0\0\0\0");
+        tiff.extend_from_slice(b"Canon\0");
+        tiff.extend_from_slice(b"\0\x01");
+        tiff.extend_from_slice(b"\x92\x7c\0\x07\0\0\0\x1a\0\0\0\x3e");
+        tiff.extend_from_slice(b"\0\0\0\0");
+        let maker_note_start = tiff.len();
+        tiff.extend_from_slice(b"\0\x01");
+        tiff.extend_from_slice(b"\0\x01\0\x07\0\0\0\x08\0\0\0\x50");
+        tiff.extend_from_slice(b"\0\0\0\0");
+        tiff.extend_from_slice(b"LENSDATA");
+        assert_eq!(0x3e, maker_note_start);
+        let maker_note = tiff[maker_note_start..].to_vec();
+
+        let mut jpeg = vec![0xff, 0xd8, 0xff, MARKER_APP1];
+        jpeg.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        jpeg.extend_from_slice(EXIF_HEADER);
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(&[0xff, MARKER_EOI]);
+        let dir = testtools::get_target_dir() + "test_jpeg";
+        fs::create_dir_all(&dir).unwrap();
+        let p = dir + "/maker-note.jpg";
+        fs::write(&p, &jpeg).unwrap();
+
+        set_exif_date_time(&p, &date_time("2001-12-29 07:00:01")).unwrap();
+
+        let after = tiff_of(&p);
+        assert_eq!(&maker_note[..], &after[maker_note_start..tiff.len()]);
+        let exif = read_exif(&p);
+        assert_eq!("2001-12-29 07:00:01", tag_value(&exif, Tag::DateTimeOriginal));
+        assert_eq!("2001-12-29 07:00:01", tag_value(&exif, Tag::DateTime));
+        assert_eq!("\"Canon\"", tag_value(&exif, Tag::Make));
+        match &exif.get_field(Tag::MakerNote, In::PRIMARY).unwrap().value {
+            Value::Undefined(data, _) => assert_eq!(&maker_note, data),
+            v => panic!("Unexpected maker note {:?}", v)
+        }
+    }
+
+    #[test]
+    fn test_not_a_jpeg() {
+        let p = copy_to_target("src/test/creation-time.mp4", "not-a-jpeg.mp4");
+        let before = fs::read(&p).unwrap();
+        assert!(matches!(set_exif_date_time(&p, &date_time("2001-12-29 07:00:01")),
+            Err(Error::UnsupportedFormat { .. })));
+        assert_eq!(before, fs::read(&p).unwrap());
+    }
+}
@@ -0,0 +1,141 @@
+use chrono::NaiveDateTime;
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the file with the last import times, kept in the root of the destination directory.
+pub const LAST_IMPORT_FILE_NAME: &str = ".phototools-last-import";
+
+/// The device name used for files without a camera make or model.
+pub const UNKNOWN_DEVICE: &str = "unknown";
+
+// Fractions of a second are kept, so the last file imported doesn't pass --since last
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// Remembers, for each camera or phone, the latest capture time of the files imported from it
+/// into a destination directory. This is what `--since last` compares with.
+///
+/// The file has one line per device: the device name, a tab and the local capture time.
+pub struct LastImports {
+    root: PathBuf,
+    // As loaded, before the imports of this run
+    previous: BTreeMap<String, NaiveDateTime>,
+    times: BTreeMap<String, NaiveDateTime>
+}
+
+impl LastImports {
+    /// Loads the last import times of the destination root, if there are any.
+    pub fn open(root: &Path) -> io::Result<LastImports> {
+        let mut times = BTreeMap::new();
+        match File::open(root.join(LAST_IMPORT_FILE_NAME)) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    let parsed = line.split_once('\t')
+                        .and_then(|(device, time)| Some((device, NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()?)));
+                    match parsed {
+                        Some((device, time)) if !device.is_empty() => { times.insert(device.to_string(), time); },
+                        _ => warn!("Ignoring invalid line in {}: {}", LAST_IMPORT_FILE_NAME, line)
+                    }
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e)
+        }
+        debug!("Loaded the last import time of {} devices from {:?}", times.len(), root);
+        Ok(LastImports { root: root.to_path_buf(), previous: times.clone(), times })
+    }
+
+    /// The device name of a camera: the model, preceded by the make unless the model already
+    /// starts with it, e.g. `Apple iPhone 12` or `Canon EOS R5`.
+    pub fn device_name(make: Option<&str>, model: Option<&str>) -> String {
+        let make = make.map(str::trim).filter(|m| !m.is_empty());
+        let model = model.map(str::trim).filter(|m| !m.is_empty());
+        let name = match (make, model) {
+            (Some(make), Some(model)) if model.to_lowercase().starts_with(&make.to_lowercase()) => model.to_string(),
+            (Some(make), Some(model)) => format!("{} {}", make, model),
+            (Some(name), None) | (None, Some(name)) => name.to_string(),
+            (None, None) => UNKNOWN_DEVICE.to_string()
+        };
+        // Keep the file format intact
+        name.replace(['\t', '\n', '\r'], " ")
+    }
+
+    /// The latest capture time imported from this device before, if any. Updates don't change it,
+    /// so files are compared with the same time whatever order they are copied in.
+    pub fn get(&self, device: &str) -> Option<NaiveDateTime> {
+        self.previous.get(device).copied()
+    }
+
+    /// Records an import from the device, keeping the latest time.
+    pub fn update(&mut self, device: &str, time: NaiveDateTime) {
+        if self.times.get(device).is_none_or(|t| time > *t) {
+            self.times.insert(device.to_string(), time);
+        }
+    }
+
+    /// Writes the times, if any changed since they were loaded.
+    pub fn save(&mut self) -> io::Result<()> {
+        if self.times == self.previous {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.root)?;
+        // Replace the file in one step, so an interrupted save doesn't lose the earlier times
+        let tmp = self.root.join(format!("{}.tmp", LAST_IMPORT_FILE_NAME));
+        let mut f = File::create(&tmp)?;
+        for (device, time) in &self.times {
+            writeln!(f, "{}\t{}", device, time.format(TIME_FORMAT))?;
+        }
+        f.sync_all()?;
+        fs::rename(&tmp, self.root.join(LAST_IMPORT_FILE_NAME))?;
+        self.previous = self.times.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_last_imports() -> io::Result<()> {
+        let root = PathBuf::from(testtools::get_target_dir() + "test_last_import");
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+
+        let mut last = LastImports::open(&root)?;
+        assert_eq!(None, last.get("Apple iPhone 12"));
+        last.save()?;
+        assert!(!root.exists(), "Nothing changed, so nothing should be written");
+
+        last.update("Apple iPhone 12", dt("2023-05-01 10:00:00"));
+        last.update("Apple iPhone 12", dt("2023-04-01 10:00:00"));
+        last.update("Canon EOS R5", dt("2023-05-02 11:00:00"));
+        assert_eq!(None, last.get("Canon EOS R5"), "Only the imports before count");
+        last.save()?;
+        assert_eq!("Apple iPhone 12\t2023-05-01T10:00:00\nCanon EOS R5\t2023-05-02T11:00:00\n",
+            fs::read_to_string(root.join(LAST_IMPORT_FILE_NAME))?);
+
+        let last = LastImports::open(&root)?;
+        assert_eq!(Some(dt("2023-05-01 10:00:00")), last.get("Apple iPhone 12"));
+        assert_eq!(None, last.get(UNKNOWN_DEVICE));
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_name() {
+        assert_eq!("Apple iPhone 12", LastImports::device_name(Some("Apple"), Some("iPhone 12")));
+        assert_eq!("Canon EOS R5", LastImports::device_name(Some("Canon"), Some("Canon EOS R5")));
+        assert_eq!("samsung", LastImports::device_name(Some("samsung "), Some("")));
+        assert_eq!("a b", LastImports::device_name(None, Some("a\tb")));
+        assert_eq!(UNKNOWN_DEVICE, LastImports::device_name(None, None));
+    }
+}
@@ -1,8 +1,30 @@
+pub mod bmff;
+pub mod config;
 pub mod copier;
+pub mod date_range;
+pub mod error;
+pub mod filename_dates;
 pub mod filetools;
+pub mod filter;
+pub mod hash;
 pub mod image;
+pub mod index;
+pub mod jpeg;
+pub mod last_import;
+pub mod link;
+pub mod manifest;
+pub mod matroska;
+pub mod mts;
+pub mod png;
+pub mod raw;
+pub mod riff;
+pub mod sniff;
+pub mod stats;
 pub mod strings;
+pub mod template;
+pub mod timestamp;
 pub mod video;
+pub mod xattr;
 
 #[cfg(test)]
 pub mod testtools;
\ No newline at end of file
@@ -0,0 +1,133 @@
+use crate::error::{Error, Result};
+
+use log::debug;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// How a file in the destination is made from its source file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LinkMode {
+    /// An independent copy
+    #[default]
+    Copy,
+    /// A hard link to the source file, which must be on the same filesystem
+    Hard,
+    /// A symbolic link to the absolute path of the source file
+    Symbolic,
+    /// A copy that shares its data with the source until either is changed, on filesystems that
+    /// support it such as Btrfs and XFS. Elsewhere a normal copy is made.
+    Reflink
+}
+
+impl LinkMode {
+    /// Parses `copy`, `hard`, `sym` or `reflink`.
+    pub fn parse(s: &str) -> Result<LinkMode> {
+        match s.to_lowercase().as_str() {
+            "copy" => Ok(LinkMode::Copy),
+            "hard" => Ok(LinkMode::Hard),
+            "sym" | "symbolic" => Ok(LinkMode::Symbolic),
+            "reflink" => Ok(LinkMode::Reflink),
+            _ => Err(Error::InvalidInput(format!("Invalid link mode '{}': use copy, hard, sym or reflink", s)))
+        }
+    }
+
+    /// Whether the target is the source file itself, so that changing its content or times
+    /// changes the source too.
+    pub fn shares_file(&self) -> bool {
+        matches!(self, LinkMode::Hard | LinkMode::Symbolic)
+    }
+
+    /// Creates `to` from the file `from`. Links and reflinks are only made when `to` doesn't exist.
+    pub fn create(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match self {
+            LinkMode::Copy => fs::copy(from, to).map(|_| ()),
+            LinkMode::Hard => fs::hard_link(from, to),
+            LinkMode::Symbolic => symlink(&fs::canonicalize(from)?, to),
+            LinkMode::Reflink => {
+                let mut src = File::open(from)?;
+                let mut dst = OpenOptions::new().write(true).create_new(true).open(to)?;
+                if let Err(e) = reflink(&src, &dst) {
+                    debug!("Unable to reflink {:?}, copying it instead: {}", from, e);
+                    io::copy(&mut src, &mut dst)?;
+                }
+                // Like fs::copy
+                dst.set_permissions(src.metadata()?.permissions())
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+// Shares the data of the source with the empty target using the FICLONE ioctl, which fails on
+// filesystems that can't do that
+#[cfg(target_os = "linux")]
+fn reflink(src: &File, dst: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Both files stay open for the duration of the call
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &File, _dst: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reflinks are only made on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools::get_target_dir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(LinkMode::Copy, LinkMode::parse("copy").unwrap());
+        assert_eq!(LinkMode::Hard, LinkMode::parse("hard").unwrap());
+        assert_eq!(LinkMode::Symbolic, LinkMode::parse("sym").unwrap());
+        assert_eq!(LinkMode::Reflink, LinkMode::parse("Reflink").unwrap());
+        assert!(LinkMode::parse("soft").is_err());
+        assert!(!LinkMode::Reflink.shares_file());
+        assert!(LinkMode::Symbolic.shares_file());
+    }
+
+    #[test]
+    fn test_create() {
+        let dir = PathBuf::from(get_target_dir() + "test_link");
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        let source = PathBuf::from(get_target_dir() + "../src/test/gps-date.jpg");
+
+        for (mode, name) in [(LinkMode::Copy, "copy.jpg"), (LinkMode::Hard, "hard.jpg"),
+                (LinkMode::Symbolic, "sym.jpg"), (LinkMode::Reflink, "reflink.jpg")] {
+            let target = dir.join(name);
+            mode.create(&source, &target).unwrap();
+            assert_eq!(fs::read(&source).unwrap(), fs::read(&target).unwrap());
+        }
+        let sym = fs::symlink_metadata(dir.join("sym.jpg")).unwrap();
+        assert!(sym.file_type().is_symlink());
+        assert_eq!(fs::canonicalize(&source).unwrap(), fs::read_link(dir.join("sym.jpg")).unwrap());
+        assert!(!fs::symlink_metadata(dir.join("reflink.jpg")).unwrap().file_type().is_symlink());
+        assert!(LinkMode::Reflink.create(&source, &dir.join("copy.jpg")).is_err(), "The target must not be replaced");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(fs::metadata(&source).unwrap().ino(), fs::metadata(dir.join("hard.jpg")).unwrap().ino());
+        }
+    }
+}
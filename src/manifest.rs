@@ -0,0 +1,129 @@
+use crate::copier::DateSource;
+use crate::error::{self, Error};
+use crate::timestamp::Timestamp;
+
+use serde::{Serialize, Serializer};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// What was done with a file.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Copied,
+    /// Would have been copied, in a dry run
+    Planned,
+    Skipped,
+    /// Already in the destination
+    Duplicate,
+    Error
+}
+
+/// One manifest line, describing what happened to one source file.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ManifestRecord {
+    #[serde(serialize_with = "lossy_path")]
+    pub source: PathBuf,
+    #[serde(serialize_with = "lossy_opt_path")]
+    pub target: Option<PathBuf>,
+    pub action: Action,
+    pub reason: Option<String>,
+    #[serde(serialize_with = "opt_display")]
+    pub timestamp: Option<Timestamp>,
+    #[serde(serialize_with = "opt_source_name")]
+    pub timestamp_source: Option<DateSource>,
+    pub size: Option<u64>,
+    pub hash: Option<String>
+}
+
+impl ManifestRecord {
+    pub fn new(source: &Path, action: Action) -> ManifestRecord {
+        ManifestRecord {
+            source: source.to_path_buf(),
+            target: None,
+            action,
+            reason: None,
+            timestamp: None,
+            timestamp_source: None,
+            size: None,
+            hash: None
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+/// Writes a JSON Lines file with one ManifestRecord per processed file.
+pub struct Manifest {
+    path: PathBuf,
+    out: BufWriter<File>
+}
+
+impl Manifest {
+    pub fn create<P: AsRef<Path>>(p: P) -> io::Result<Manifest> {
+        Ok(Manifest {
+            path: p.as_ref().to_path_buf(),
+            out: BufWriter::new(File::create(p)?)
+        })
+    }
+
+    pub fn write(&mut self, record: &ManifestRecord) -> error::Result<()> {
+        let json = record.to_json()?;
+        // Flush every line, the manifest is an audit trail that should survive a crash
+        writeln!(self.out, "{}", json)
+            .and_then(|_| self.out.flush())
+            .map_err(|e| Error::io(&self.path, e))
+    }
+}
+
+// Paths that aren't valid UTF-8 are written with replacement characters rather than failing
+fn lossy_path<S: Serializer>(p: &Path, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&p.to_string_lossy())
+}
+
+fn lossy_opt_path<S: Serializer>(p: &Option<PathBuf>, s: S) -> Result<S::Ok, S::Error> {
+    match p {
+        Some(p) => lossy_path(p, s),
+        None => s.serialize_none()
+    }
+}
+
+fn opt_display<S: Serializer>(t: &Option<Timestamp>, s: S) -> Result<S::Ok, S::Error> {
+    match t {
+        Some(t) => s.collect_str(t),
+        None => s.serialize_none()
+    }
+}
+
+fn opt_source_name<S: Serializer>(d: &Option<DateSource>, s: S) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => s.serialize_str(d.name()),
+        None => s.serialize_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let mut rec = ManifestRecord::new(Path::new("/src/a \"b\".jpg"), Action::Copied);
+        rec.target = Some(PathBuf::from("/dst/2019/2019-04-27/a \"b\".jpg"));
+        rec.timestamp = Timestamp::parse_floating("2019-04-27 14:08:01");
+        rec.timestamp_source = Some(DateSource::Gps);
+        rec.size = Some(204636);
+        rec.hash = Some("abc".to_string());
+        assert_eq!("{\"source\":\"/src/a \\\"b\\\".jpg\",\"target\":\"/dst/2019/2019-04-27/a \\\"b\\\".jpg\",\
+            \"action\":\"copied\",\"reason\":null,\"timestamp\":\"2019-04-27T14:08:01\",\
+            \"timestamp_source\":\"gps\",\"size\":204636,\"hash\":\"abc\"}", rec.to_json().unwrap());
+
+        let mut rec = ManifestRecord::new(Path::new("x\\y\n\u{1}"), Action::Skipped);
+        rec.reason = Some("hidden".to_string());
+        assert_eq!("{\"source\":\"x\\\\y\\n\\u0001\",\"target\":null,\"action\":\"skipped\",\"reason\":\"hidden\",\
+            \"timestamp\":null,\"timestamp_source\":null,\"size\":null,\"hash\":null}", rec.to_json().unwrap());
+    }
+}
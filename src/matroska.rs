@@ -0,0 +1,143 @@
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const EBML: u32 = 0x1a45_dfa3;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_a966;
+const DATE_UTC: u32 = 0x4461;
+const CLUSTER: u32 = 0x1f43_b675;
+
+/// An EBML element of a Matroska or WebM file.
+#[derive(Debug)]
+struct Element {
+    id: u32,
+    data_start: u64,
+    // None when the size is unknown, as in live streams
+    end: Option<u64>
+}
+
+pub fn read_date_utc_from_file<P: AsRef<Path>>(p: P) -> io::Result<Option<NaiveDateTime>> {
+    read_date_utc(&mut BufReader::new(File::open(p)?))
+}
+
+/// The `Segment/Info/DateUTC` of a Matroska or WebM file: when the recording started, in UTC.
+pub fn read_date_utc<R: Read + Seek>(r: &mut R) -> io::Result<Option<NaiveDateTime>> {
+    let len = r.seek(SeekFrom::End(0))?;
+    r.seek(SeekFrom::Start(0))?;
+    let header = read_element(r, 0, len)?;
+    if header.as_ref().map(|e| e.id) != Some(EBML) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a Matroska file"));
+    }
+
+    let mut pos = header.and_then(|e| e.end).unwrap_or(len);
+    while let Some(element) = read_element(r, pos, len)? {
+        let end = element.end.unwrap_or(len);
+        if element.id == SEGMENT {
+            return read_segment_date(r, element.data_start, end);
+        }
+        pos = end;
+    }
+    Ok(None)
+}
+
+// The Info element comes before the clusters with the audio and video data
+fn read_segment_date<R: Read + Seek>(r: &mut R, start: u64, end: u64) -> io::Result<Option<NaiveDateTime>> {
+    let mut pos = start;
+    while let Some(element) = read_element(r, pos, end)? {
+        match (element.id, element.end) {
+            (INFO, Some(info_end)) => {
+                let mut child_pos = element.data_start;
+                while let Some(child) = read_element(r, child_pos, info_end)? {
+                    let child_end = child.end.unwrap_or(info_end);
+                    if child.id == DATE_UTC && child_end - child.data_start == 8 {
+                        r.seek(SeekFrom::Start(child.data_start))?;
+                        let mut buf = [0; 8];
+                        r.read_exact(&mut buf)?;
+                        return Ok(from_matroska_date(i64::from_be_bytes(buf)));
+                    }
+                    child_pos = child_end;
+                }
+                return Ok(None);
+            },
+            (CLUSTER, _) | (_, None) => return Ok(None),
+            (_, Some(element_end)) => pos = element_end
+        }
+    }
+    Ok(None)
+}
+
+// Nanoseconds since the start of the millennium
+fn from_matroska_date(nanos: i64) -> Option<NaiveDateTime> {
+    let epoch = NaiveDate::from_ymd_opt(2001, 1, 1)?.and_hms_opt(0, 0, 0)?;
+    epoch.checked_add_signed(Duration::seconds(nanos.div_euclid(1_000_000_000)))
+}
+
+fn read_element<R: Read + Seek>(r: &mut R, pos: u64, end: u64) -> io::Result<Option<Element>> {
+    if pos + 2 > end {
+        return Ok(None);
+    }
+    r.seek(SeekFrom::Start(pos))?;
+    let (id, id_len) = match read_vint(r, true)? {
+        Some(v) => v,
+        None => return Ok(None)
+    };
+    let (size, size_len) = match read_vint(r, false)? {
+        Some(v) => v,
+        None => return Ok(None)
+    };
+    let data_start = pos + id_len + size_len;
+    // All ones means the size is unknown
+    let unknown = size == (1u64 << (7 * size_len)) - 1;
+    let element_end = if unknown { None } else { data_start.checked_add(size) };
+    if element_end.is_some_and(|e| e > end) || data_start > end {
+        // Truncated or corrupt element, don't look any further
+        return Ok(None);
+    }
+    Ok(Some(Element { id: id as u32, data_start, end: element_end }))
+}
+
+// A variable length integer, its length is given by the leading zero bits of the first byte. IDs keep
+// the length marker bit, sizes don't.
+fn read_vint<R: Read>(r: &mut R, keep_marker: bool) -> io::Result<Option<(u64, u64)>> {
+    let mut first = [0; 1];
+    r.read_exact(&mut first)?;
+    let len = first[0].leading_zeros() as u64 + 1;
+    if len > 8 || (keep_marker && len > 4) {
+        return Ok(None);
+    }
+    let mut value = if keep_marker { first[0] as u64 } else { (first[0] as u64) & (0xff >> len) };
+    for _ in 1..len {
+        r.read_exact(&mut first)?;
+        value = (value << 8) | first[0] as u64;
+    }
+    Ok(Some((value, len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_date_utc() {
+        let dt = read_date_utc_from_file(testtools::get_base_dir() + "src/testvideo/screen.mkv").unwrap();
+        assert_eq!("2022-11-05 20:45:10", dt.unwrap().format("%Y-%m-%d %H:%M:%S").to_string());
+        let dt = read_date_utc_from_file(testtools::get_base_dir() + "src/testvideo/browser.webm").unwrap();
+        assert_eq!(None, dt);
+    }
+
+    #[test]
+    fn test_not_matroska() {
+        assert!(read_date_utc(&mut Cursor::new(b"RIFF\0\0\0\0AVI ".to_vec())).is_err());
+        assert!(read_date_utc(&mut Cursor::new(vec![0x1a, 0x45])).is_err());
+    }
+
+    #[test]
+    fn test_from_matroska_date() {
+        assert_eq!(NaiveDate::from_ymd_opt(2000, 12, 31).unwrap().and_hms_opt(23, 59, 59),
+            from_matroska_date(-1));
+    }
+}
@@ -0,0 +1,95 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// How much of the stream is searched for the recording time. AVCHD cameras write it with every
+/// video frame, so it's found near the start of the file.
+const SEARCH_LEN: u64 = 4 * 1024 * 1024;
+
+/// The H.264 user data that holds the AVCHD Modified Digital Video Pack Metadata (MDPM).
+const MDPM_UUID: [u8; 16] = [0x17, 0xee, 0x8c, 0x60, 0xf8, 0x4d, 0x11, 0xd9,
+    0x8c, 0xd6, 0x08, 0x00, 0x20, 0x0c, 0x9a, 0x66];
+
+// The MDPM tags with the date (after a time zone byte: year and month) and the time (day, hour,
+// minute and second), all in BCD
+const TAG_DATE: u8 = 0x18;
+const TAG_TIME: u8 = 0x19;
+
+/// The recording time of an AVCHD stream (MTS, M2TS). It's the camera's local time.
+pub fn read_recording_time<P: AsRef<Path>>(p: P) -> io::Result<Option<NaiveDateTime>> {
+    let mut data = Vec::new();
+    File::open(p)?.take(SEARCH_LEN).read_to_end(&mut data)?;
+    Ok(find_recording_time(&data))
+}
+
+fn find_recording_time(data: &[u8]) -> Option<NaiveDateTime> {
+    let mut pos = 0;
+    while let Some(i) = find(&data[pos..], &MDPM_UUID) {
+        let start = pos + i + MDPM_UUID.len();
+        if let Some(dt) = data.get(start..).and_then(parse_mdpm) {
+            return Some(dt);
+        }
+        pos = start;
+    }
+    None
+}
+
+// 'MDPM', the number of tags, then for every tag its number and 4 bytes of data
+fn parse_mdpm(data: &[u8]) -> Option<NaiveDateTime> {
+    if !data.starts_with(b"MDPM") {
+        return None;
+    }
+    let count = *data.get(4)? as usize;
+    let mut date = None;
+    let mut time = None;
+    for tag in data.get(5..5 + count * 5)?.chunks_exact(5) {
+        match tag[0] {
+            TAG_DATE => date = Some([tag[2], tag[3], tag[4]]),
+            TAG_TIME => time = Some([tag[1], tag[2], tag[3], tag[4]]),
+            _ => {}
+        }
+    }
+    let (date, time) = (date?, time?);
+    let year = bcd(date[0])? * 100 + bcd(date[1])?;
+    NaiveDate::from_ymd_opt(year as i32, bcd(date[2])?, bcd(time[0])?)?
+        .and_hms_opt(bcd(time[1])?, bcd(time[2])?, bcd(time[3])?)
+}
+
+fn bcd(b: u8) -> Option<u32> {
+    let (high, low) = (b >> 4, b & 0x0f);
+    if high > 9 || low > 9 {
+        return None;
+    }
+    Some((high * 10 + low) as u32)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+
+    #[test]
+    fn test_find_recording_time() {
+        let mut data = vec![0x47; 100];
+        data.extend_from_slice(&MDPM_UUID);
+        data.extend_from_slice(b"MDPM\x03");
+        data.extend_from_slice(&[0x18, 0x02, 0x20, 0x12, 0x07]);
+        data.extend_from_slice(&[0x19, 0x14, 0x18, 0x30, 0x05]);
+        data.extend_from_slice(&[0x70, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(NaiveDate::from_ymd_opt(2012, 7, 14).unwrap().and_hms_opt(18, 30, 5), find_recording_time(&data));
+        // Truncated
+        assert_eq!(None, find_recording_time(&data[..data.len() - 8]));
+        assert_eq!(None, find_recording_time(b"MDPM"));
+    }
+
+    #[test]
+    fn test_read_recording_time() {
+        let dt = read_recording_time(testtools::get_base_dir() + "src/testvideo/camcorder.mts").unwrap();
+        assert_eq!("2012-07-14 18:30:05", dt.unwrap().format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+}
@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// The keyword of the text chunk with the time the image was created.
+pub const CREATION_TIME: &str = "Creation Time";
+
+/// The value of the first `tEXt` or uncompressed `iTXt` chunk with this keyword. Text chunks
+/// after the image data are not looked at.
+pub fn read_text<P: AsRef<Path>>(p: P, keyword: &str) -> io::Result<Option<String>> {
+    let mut r = BufReader::new(File::open(p)?);
+    let mut signature = [0; 8];
+    r.read_exact(&mut signature)?;
+    if signature != PNG_SIGNATURE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a PNG file"));
+    }
+
+    loop {
+        let mut header = [0; 8];
+        r.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let chunk_type = &header[4..8];
+        if chunk_type == b"IDAT" || chunk_type == b"IEND" {
+            return Ok(None);
+        }
+        if chunk_type == b"tEXt" || chunk_type == b"iTXt" {
+            let mut data = Vec::new();
+            (&mut r).take(len).read_to_end(&mut data)?;
+            let text = if chunk_type == b"tEXt" { parse_text(&data, keyword) } else { parse_itxt(&data, keyword) };
+            if text.is_some() {
+                return Ok(text);
+            }
+            io::copy(&mut (&mut r).take(4), &mut io::sink())?;
+        } else {
+            // Chunk data and CRC
+            io::copy(&mut (&mut r).take(len + 4), &mut io::sink())?;
+        }
+    }
+}
+
+// Keyword, null, Latin-1 text
+fn parse_text(data: &[u8], keyword: &str) -> Option<String> {
+    let (key, text) = split_null(data)?;
+    if key != keyword.as_bytes() {
+        return None;
+    }
+    Some(text.iter().map(|&b| b as char).collect())
+}
+
+// Keyword, null, compression flag and method, language tag, null, translated keyword, null, UTF-8 text
+fn parse_itxt(data: &[u8], keyword: &str) -> Option<String> {
+    let (key, rest) = split_null(data)?;
+    if key != keyword.as_bytes() || rest.first() != Some(&0) {
+        return None;
+    }
+    let (_language, rest) = split_null(rest.get(2..)?)?;
+    let (_translated, text) = split_null(rest)?;
+    Some(String::from_utf8_lossy(text).into_owned())
+}
+
+fn split_null(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let i = data.iter().position(|&b| b == 0)?;
+    Some((&data[..i], &data[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+
+    #[test]
+    fn test_read_text() {
+        let text = read_text(testtools::get_base_dir() + "src/testimage/screenshot.png", CREATION_TIME).unwrap();
+        assert_eq!(Some("Tue, 14 Mar 2023 09:26:53 +0100".to_string()), text);
+        let text = read_text(testtools::get_base_dir() + "src/testimage/photo.png", CREATION_TIME).unwrap();
+        assert_eq!(None, text);
+        assert!(read_text(testtools::get_base_dir() + "src/test/gps-date.jpg", CREATION_TIME).is_err());
+    }
+
+    #[test]
+    fn test_parse_chunks() {
+        assert_eq!(Some("2019 \u{e9}t\u{e9}".to_string()), parse_text(b"Creation Time\x002019 \xe9t\xe9", CREATION_TIME));
+        assert_eq!(None, parse_text(b"Software\0GIMP", CREATION_TIME));
+        assert_eq!(Some("2019-04-27T14:08:01".to_string()),
+            parse_itxt(b"Creation Time\0\0\0en\0Erstellt\x002019-04-27T14:08:01", CREATION_TIME));
+        // Compressed
+        assert_eq!(None, parse_itxt(b"Creation Time\0\x01\0\0\0x\x9c", CREATION_TIME));
+    }
+}
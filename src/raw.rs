@@ -0,0 +1,284 @@
+use crate::bmff;
+
+use exif::experimental::Writer;
+use exif::{Context, Exif, Field, In, Tag, Value};
+use log::debug;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+/// The header of a Fujifilm RAF file. The EXIF data is in an embedded JPEG preview.
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW";
+
+/// Larger values, such as the JPEG preview RW2 files keep in IFD0, are left out when the
+/// metadata of an ORF or RW2 file is copied.
+const MAX_VALUE_SIZE: u64 = 64 * 1024;
+
+/// Reads the EXIF data from a JPEG, HEIC or TIFF file, or from any of the supported RAW formats.
+/// CR2, NEF, ARW and DNG are TIFF files, ORF and RW2 are TIFF files with their own magic number,
+/// RAF keeps its EXIF data in a JPEG preview and CR3 in TIFF structures inside an ISO base media file.
+pub fn read_exif<R: Read + Seek>(r: &mut R) -> Result<Exif, exif::Error> {
+    let mut header = [0; 16];
+    let len = read_up_to(r, &mut header)?;
+    let header = &header[..len];
+    r.seek(SeekFrom::Start(0))?;
+
+    if header.starts_with(b"IIRO") || header.starts_with(b"IIRS") || header.starts_with(b"MMOR")
+        || header.starts_with(b"IIU\0") {
+        // Olympus ORF and Panasonic RW2
+        debug!("Reading TIFF with magic {:?}", &header[..4]);
+        read_tiff_variant(r, header)
+    } else if header.starts_with(RAF_MAGIC) {
+        read_raf(r)
+    } else if header.len() >= 12 && &header[4..12] == b"ftypcrx " {
+        read_cr3(r)
+    } else {
+        exif::Reader::new().read_from_container(&mut std::io::BufReader::new(r))
+    }
+}
+
+fn read_up_to<R: Read>(r: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n
+        }
+    }
+    Ok(len)
+}
+
+// The offset and length of the JPEG preview are big endian numbers at offset 84
+fn read_raf<R: Read + Seek>(r: &mut R) -> Result<Exif, exif::Error> {
+    let mut pointers = [0; 8];
+    r.seek(SeekFrom::Start(84))?;
+    r.read_exact(&mut pointers)?;
+    let offset = u32::from_be_bytes([pointers[0], pointers[1], pointers[2], pointers[3]]);
+    let len = u32::from_be_bytes([pointers[4], pointers[5], pointers[6], pointers[7]]);
+
+    let mut jpeg = Vec::new();
+    r.seek(SeekFrom::Start(offset as u64))?;
+    r.take(len as u64).read_to_end(&mut jpeg)?;
+    if jpeg.len() != len as usize {
+        return Err(exif::Error::InvalidFormat("Truncated RAF preview"));
+    }
+    exif::Reader::new().read_from_container(&mut Cursor::new(jpeg))
+}
+
+// ORF and RW2 files are TIFF files with another magic number, and with the raw image data in the
+// same file. Only the IFDs with metadata are read, following their offsets, and copied into a new
+// TIFF structure with the standard magic number.
+fn read_tiff_variant<R: Read + Seek>(r: &mut R, header: &[u8]) -> Result<Exif, exif::Error> {
+    if header.len() < 8 {
+        return Err(exif::Error::InvalidFormat("Truncated TIFF header"));
+    }
+    let mut copy = TiffCopy { r, le: header[0] == b'I', out: Vec::new() };
+    copy.out.extend_from_slice(if copy.le { b"II*\0" } else { b"MM\0*" });
+    copy.out.extend_from_slice(&copy.u32_bytes(8));
+    let ifd0 = copy.u32(&header[4..8]);
+    copy.copy_ifd(ifd0, 0)?;
+    exif::Reader::new().read_raw(copy.out)
+}
+
+struct TiffCopy<'a, R> {
+    r: &'a mut R,
+    le: bool,
+    out: Vec<u8>
+}
+
+impl<'a, R: Read + Seek> TiffCopy<'a, R> {
+    // Copies the IFD at the offset in the file to the end of the output, with the IFDs it points
+    // to, and returns its offset in the output. The thumbnail IFD that may follow is left out.
+    fn copy_ifd(&mut self, offset: u32, depth: usize) -> Result<u32, exif::Error> {
+        let mut count = [0; 2];
+        self.r.seek(SeekFrom::Start(offset as u64))?;
+        self.r.read_exact(&mut count)?;
+        let mut entries = vec![0; self.u16(&count) as usize * 12];
+        self.r.read_exact(&mut entries)?;
+
+        let mut kept = Vec::new();
+        let mut values = Vec::new();
+        for entry in entries.chunks(12) {
+            let tag = self.u16(&entry[0..2]);
+            let size = match type_size(self.u16(&entry[2..4])) {
+                Some(s) => s * self.u32(&entry[4..8]) as u64,
+                None => continue
+            };
+            if [Tag::ExifIFDPointer, Tag::GPSInfoIFDPointer, Tag::InteropIFDPointer].iter().any(|p| p.number() == tag) {
+                // Pointers can't point back as they only go one level deeper
+                if depth < 2 {
+                    kept.push((entry.to_vec(), Some(self.u32(&entry[8..12])), None));
+                }
+            } else if size <= 4 {
+                kept.push((entry.to_vec(), None, None));
+            } else if size <= MAX_VALUE_SIZE {
+                let mut value = vec![0; size as usize];
+                self.r.seek(SeekFrom::Start(self.u32(&entry[8..12]) as u64))?;
+                self.r.read_exact(&mut value)?;
+                kept.push((entry.to_vec(), None, Some(values.len())));
+                values.push(value);
+            }
+        }
+
+        let start = self.out.len();
+        let values_start = start + 2 + kept.len() * 12 + 4;
+        self.out.resize(values_start, 0);
+        let count = self.u16_bytes(kept.len() as u16);
+        self.out[start..start + 2].copy_from_slice(&count);
+        for (i, (mut entry, pointer, value)) in kept.into_iter().enumerate() {
+            if let Some(p) = pointer {
+                let new_offset = self.copy_ifd(p, depth + 1)?;
+                entry[8..12].copy_from_slice(&self.u32_bytes(new_offset));
+            } else if let Some(v) = value {
+                // Values start on a word boundary
+                if self.out.len() % 2 == 1 {
+                    self.out.push(0);
+                }
+                entry[8..12].copy_from_slice(&self.u32_bytes(self.out.len() as u32));
+                self.out.extend_from_slice(&values[v]);
+            }
+            let pos = start + 2 + i * 12;
+            self.out[pos..pos + 12].copy_from_slice(&entry);
+        }
+        Ok(start as u32)
+    }
+
+    fn u16(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.le { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) }
+    }
+
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.le { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+    }
+
+    fn u16_bytes(&self, v: u16) -> [u8; 2] {
+        if self.le { v.to_le_bytes() } else { v.to_be_bytes() }
+    }
+
+    fn u32_bytes(&self, v: u32) -> [u8; 4] {
+        if self.le { v.to_le_bytes() } else { v.to_be_bytes() }
+    }
+}
+
+// The size in bytes of one value of a TIFF field type
+fn type_size(field_type: u16) -> Option<u64> {
+    match field_type {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None
+    }
+}
+
+// Every CMT box is a TIFF with a single IFD. They are read one by one, and the fields are put in
+// the IFD they belong to in a new TIFF structure that can be read as a whole.
+fn read_cr3<R: Read + Seek>(r: &mut R) -> Result<Exif, exif::Error> {
+    let mut parts = Vec::new();
+    for (box_type, data) in bmff::read_cr3_metadata(r)? {
+        let context = match &box_type {
+            b"CMT1" => Context::Tiff,
+            b"CMT2" => Context::Exif,
+            b"CMT4" => Context::Gps,
+            _ => continue
+        };
+        match exif::Reader::new().read_raw(data) {
+            Ok(exif) => parts.push((context, exif)),
+            Err(e) => debug!("Skipping CR3 box {:?}: {}", String::from_utf8_lossy(&box_type), e)
+        }
+    }
+    if parts.is_empty() {
+        return Err(exif::Error::NotFound("CR3"));
+    }
+
+    let fields: Vec<Field> = parts.iter()
+        .flat_map(|(context, exif)| exif.fields()
+            .filter(|f| f.ifd_num == In::PRIMARY && !matches!(f.value, Value::Unknown(..)))
+            .filter(|f| !is_pointer(f.tag))
+            .map(move |f| Field { tag: Tag(*context, f.tag.number()), ifd_num: In::PRIMARY, value: f.value.clone() }))
+        .collect();
+
+    let mut writer = Writer::new();
+    for f in &fields {
+        writer.push_field(f);
+    }
+    let mut buf = Cursor::new(Vec::new());
+    writer.write(&mut buf, parts[0].1.little_endian())?;
+    exif::Reader::new().read_raw(buf.into_inner())
+}
+
+// The writer creates the pointers to the Exif and GPS IFDs itself
+fn is_pointer(tag: Tag) -> bool {
+    [Tag::ExifIFDPointer, Tag::GPSInfoIFDPointer, Tag::InteropIFDPointer].iter()
+        .any(|p| p.number() == tag.number())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+    use std::fs::File;
+
+    fn read(name: &str) -> Exif {
+        let path = testtools::get_base_dir() + "src/testraw/" + name;
+        read_exif(&mut File::open(&path).unwrap()).unwrap()
+    }
+
+    fn ascii(exif: &Exif, tag: Tag) -> String {
+        let field = exif.get_field(tag, In::PRIMARY).unwrap_or_else(|| panic!("No {}", tag));
+        field.display_value().to_string()
+    }
+
+    #[test]
+    fn test_raw_formats() {
+        let expected = [
+            ("canon.cr2", "Canon", "Canon EOS 5D Mark IV", "2021-06-15 10:00:01"),
+            ("canon.cr3", "Canon", "Canon EOS R5", "2021-06-15 10:00:02"),
+            ("nikon.nef", "NIKON CORPORATION", "NIKON Z 6", "2021-06-15 10:00:03"),
+            ("sony.arw", "SONY", "ILCE-7M3", "2021-06-15 10:00:04"),
+            ("fujifilm.raf", "FUJIFILM", "X-T4", "2021-06-15 10:00:05"),
+            ("olympus.orf", "OLYMPUS CORPORATION", "E-M1MarkIII", "2021-06-15 10:00:06"),
+            ("panasonic.rw2", "Panasonic", "DC-GH5", "2021-06-15 10:00:07")
+        ];
+        for (name, make, model, date) in expected.iter() {
+            let exif = read(name);
+            assert_eq!(format!("\"{}\"", make), ascii(&exif, Tag::Make), "{}", name);
+            assert_eq!(format!("\"{}\"", model), ascii(&exif, Tag::Model), "{}", name);
+            assert_eq!(*date, ascii(&exif, Tag::DateTimeOriginal), "{}", name);
+        }
+        assert_eq!("\"+02:00\"", ascii(&read("canon.cr3"), Tag::OffsetTimeOriginal));
+    }
+
+    #[test]
+    fn test_large_value_left_out() {
+        // An ORF with DateTime and a preview in IFD0, followed by an Exif IFD with DateTimeOriginal
+        let mut orf = b"IIRO\x08\0\0\0".to_vec();
+        let ifd0 = [(0x0132, 2, 20, 50), (0x002e, 7, 100_000, 70), (0x8769, 4, 1, 100_070)];
+        orf.extend_from_slice(&(ifd0.len() as u16).to_le_bytes());
+        for (tag, typ, count, value) in ifd0.iter() {
+            orf.extend_from_slice(&(*tag as u16).to_le_bytes());
+            orf.extend_from_slice(&(*typ as u16).to_le_bytes());
+            orf.extend_from_slice(&(*count as u32).to_le_bytes());
+            orf.extend_from_slice(&(*value as u32).to_le_bytes());
+        }
+        orf.extend_from_slice(&[0; 4]);
+        orf.extend_from_slice(b"2021:06:15 10:00:06\0");
+        orf.resize(100_070, 0xff);
+        orf.extend_from_slice(&[1, 0, 0x03, 0x90, 2, 0, 20, 0, 0, 0]);
+        orf.extend_from_slice(&100_088u32.to_le_bytes());
+        orf.extend_from_slice(&[0; 4]);
+        orf.extend_from_slice(b"2021:06:15 10:00:07\0");
+
+        let exif = read_exif(&mut Cursor::new(orf)).unwrap();
+        assert_eq!("2021-06-15 10:00:06", ascii(&exif, Tag::DateTime));
+        assert_eq!("2021-06-15 10:00:07", ascii(&exif, Tag::DateTimeOriginal));
+        assert!(exif.get_field(Tag(Context::Tiff, 0x002e), In::PRIMARY).is_none());
+    }
+
+    #[test]
+    fn test_not_raw() {
+        let path = testtools::get_base_dir() + "src/test/creation-time.mp4";
+        assert!(read_exif(&mut File::open(&path).unwrap()).is_err());
+        assert!(read_exif(&mut Cursor::new(b"IIU".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// A chunk of a RIFF file (AVI, WebP).
+#[derive(Debug)]
+struct Chunk {
+    id: [u8; 4],
+    // Position of the chunk content, after the header
+    data_start: u64,
+    end: u64
+}
+
+/// Reads the first chunk with this id, looking inside `LIST` chunks except for the `movi` list,
+/// which only holds the audio and video data.
+pub fn read_chunk<R: Read + Seek>(r: &mut R, id: &[u8; 4]) -> io::Result<Option<Vec<u8>>> {
+    let len = r.seek(SeekFrom::End(0))?;
+    r.seek(SeekFrom::Start(0))?;
+    let mut header = [0; 12];
+    r.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a RIFF file"));
+    }
+    let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+    match find_chunk(r, 12, (8 + size).min(len), id)? {
+        Some(chunk) => {
+            r.seek(SeekFrom::Start(chunk.data_start))?;
+            let mut data = vec![0; (chunk.end - chunk.data_start) as usize];
+            r.read_exact(&mut data)?;
+            Ok(Some(data))
+        },
+        None => Ok(None)
+    }
+}
+
+/// The `IDIT` date of an AVI file, as found in the file. Cameras write it in the `ctime` format,
+/// e.g. `FRI JAN 01 12:00:00 2010`, or as `2010:01:01 12:00:00`.
+pub fn read_avi_date<P: AsRef<Path>>(p: P) -> io::Result<Option<String>> {
+    let mut r = BufReader::new(File::open(p)?);
+    Ok(read_chunk(&mut r, b"IDIT")?
+        .map(|data| String::from_utf8_lossy(&data).trim_end_matches(['\0', '\r', '\n']).trim().to_string()))
+}
+
+fn find_chunk<R: Read + Seek>(r: &mut R, start: u64, end: u64, id: &[u8; 4]) -> io::Result<Option<Chunk>> {
+    for chunk in read_chunks(r, start, end)? {
+        if &chunk.id == id {
+            return Ok(Some(chunk));
+        }
+        if &chunk.id == b"LIST" && chunk.end - chunk.data_start >= 4 {
+            r.seek(SeekFrom::Start(chunk.data_start))?;
+            let mut list_type = [0; 4];
+            r.read_exact(&mut list_type)?;
+            if &list_type == b"movi" {
+                continue;
+            }
+            if let Some(found) = find_chunk(r, chunk.data_start + 4, chunk.end, id)? {
+                return Ok(Some(found));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn read_chunks<R: Read + Seek>(r: &mut R, start: u64, end: u64) -> io::Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        r.seek(SeekFrom::Start(pos))?;
+        let mut header = [0; 8];
+        r.read_exact(&mut header)?;
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        if size > end - pos - 8 {
+            // Truncated or corrupt chunk, don't look any further
+            break;
+        }
+        chunks.push(Chunk { id: [header[0], header[1], header[2], header[3]], data_start: pos + 8, end: pos + 8 + size });
+        // Chunks are padded to an even size
+        pos += 8 + size + (size & 1);
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut c = id.to_vec();
+        c.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        c.extend_from_slice(content);
+        if content.len() % 2 == 1 {
+            c.push(0);
+        }
+        c
+    }
+
+    #[test]
+    fn test_read_chunk() {
+        let mut movi = b"movi".to_vec();
+        movi.extend(chunk(b"IDIT", b"in the movie data"));
+        let mut hdrl = b"hdrl".to_vec();
+        hdrl.extend(chunk(b"avih", b"odd"));
+        hdrl.extend(chunk(b"IDIT", b"2010:01:01 12:00:00\0"));
+        let mut content = b"AVI ".to_vec();
+        content.extend(chunk(b"LIST", &movi));
+        content.extend(chunk(b"LIST", &hdrl));
+        let file = chunk(b"RIFF", &content);
+
+        let idit = read_chunk(&mut Cursor::new(file), b"IDIT").unwrap();
+        assert_eq!(Some(b"2010:01:01 12:00:00\0".to_vec()), idit);
+    }
+
+    #[test]
+    fn test_read_avi_date() {
+        let date = read_avi_date(testtools::get_base_dir() + "src/testvideo/camera.avi").unwrap();
+        assert_eq!(Some("TUE MAR 02 09:15:30 2010".to_string()), date);
+        assert!(read_avi_date(testtools::get_base_dir() + "src/test/gps-date.jpg").is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use log::warn;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The number of bytes read from the start of a file to recognise its format, enough for two
+/// MPEG transport stream packets.
+const HEADER_LEN: usize = 200;
+
+/// The first byte of every MPEG transport stream packet.
+const TS_SYNC: u8 = 0x47;
+
+/// A file format, as recognised by its content or guessed from its extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    Jpeg,
+    /// TIFF based, which includes DNG, CR2, NEF and ARW
+    Tiff,
+    /// Olympus RAW, a TIFF with its own magic number
+    Orf,
+    /// Panasonic RAW, a TIFF with its own magic number
+    Rw2,
+    /// Fujifilm RAW
+    Raf,
+    /// Canon RAW, an ISO base media file
+    Cr3,
+    Heic,
+    Avif,
+    Png,
+    Gif,
+    WebP,
+    Mp4,
+    QuickTime,
+    ThreeGp,
+    Avi,
+    /// Matroska and WebM
+    Matroska,
+    /// MPEG transport stream, as used by AVCHD camcorders
+    Mts
+}
+
+/// Which handler reads the timestamp of a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Media {
+    Photo,
+    Video
+}
+
+impl FileKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileKind::Jpeg => "JPEG",
+            FileKind::Tiff => "TIFF",
+            FileKind::Orf => "ORF",
+            FileKind::Rw2 => "RW2",
+            FileKind::Raf => "RAF",
+            FileKind::Cr3 => "CR3",
+            FileKind::Heic => "HEIC",
+            FileKind::Avif => "AVIF",
+            FileKind::Png => "PNG",
+            FileKind::Gif => "GIF",
+            FileKind::WebP => "WebP",
+            FileKind::Mp4 => "MP4",
+            FileKind::QuickTime => "QuickTime",
+            FileKind::ThreeGp => "3GP",
+            FileKind::Avi => "AVI",
+            FileKind::Matroska => "Matroska",
+            FileKind::Mts => "MPEG-TS"
+        }
+    }
+
+    /// The handler for this kind of file.
+    pub fn media(&self) -> Media {
+        match self {
+            FileKind::Jpeg | FileKind::Tiff | FileKind::Orf | FileKind::Rw2 | FileKind::Raf | FileKind::Cr3
+                | FileKind::Heic | FileKind::Avif | FileKind::Png | FileKind::Gif | FileKind::WebP => Media::Photo,
+            FileKind::Mp4 | FileKind::QuickTime | FileKind::ThreeGp | FileKind::Avi | FileKind::Matroska
+                | FileKind::Mts => Media::Video
+        }
+    }
+
+    /// The kind of file the extension is used for, lowercase and without the dot.
+    pub fn from_extension(ext: &str) -> Option<FileKind> {
+        match ext {
+            "jpg" | "jpeg" => Some(FileKind::Jpeg),
+            "tif" | "tiff" | "dng" | "cr2" | "nef" | "arw" => Some(FileKind::Tiff),
+            "orf" => Some(FileKind::Orf),
+            "rw2" => Some(FileKind::Rw2),
+            "raf" => Some(FileKind::Raf),
+            "cr3" => Some(FileKind::Cr3),
+            "heic" | "heif" => Some(FileKind::Heic),
+            "avif" => Some(FileKind::Avif),
+            "png" => Some(FileKind::Png),
+            "gif" => Some(FileKind::Gif),
+            "webp" => Some(FileKind::WebP),
+            "mp4" | "m4v" => Some(FileKind::Mp4),
+            "mov" | "qt" => Some(FileKind::QuickTime),
+            "3gp" | "3g2" => Some(FileKind::ThreeGp),
+            "avi" => Some(FileKind::Avi),
+            "mkv" | "webm" => Some(FileKind::Matroska),
+            // Not .ts, which is more often TypeScript
+            "mts" | "m2ts" | "m2t" => Some(FileKind::Mts),
+            _ => None
+        }
+    }
+
+    /// The kind of file the extension of the path is used for.
+    pub fn from_path(p: &Path) -> Option<FileKind> {
+        FileKind::from_extension(&p.extension()?.to_string_lossy().to_lowercase())
+    }
+
+    /// Whether a file with this content may have the other kind's extension. MP4, QuickTime
+    /// and 3GP files are all ISO base media files, and their brands and extensions are mixed freely.
+    pub fn matches(&self, other: FileKind) -> bool {
+        let iso_video = |k: FileKind| matches!(k, FileKind::Mp4 | FileKind::QuickTime | FileKind::ThreeGp);
+        *self == other || (iso_video(*self) && iso_video(other))
+    }
+}
+
+/// The format of a file by its content, or by its extension when the content isn't recognised.
+/// Logs a warning when the two disagree.
+pub fn detect(p: &Path) -> io::Result<Option<FileKind>> {
+    let by_extension = FileKind::from_path(p);
+    match (sniff_file(p)?, by_extension) {
+        (Some(content), Some(ext)) if !content.matches(ext) => {
+            warn!("{} has a {} extension but is a {} file", p.to_string_lossy(), ext.name(), content.name());
+            Ok(Some(content))
+        },
+        (Some(content), _) => Ok(Some(content)),
+        (None, ext) => Ok(ext)
+    }
+}
+
+/// Recognises the format of a file by its first bytes.
+pub fn sniff_file<P: AsRef<Path>>(p: P) -> io::Result<Option<FileKind>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(p)?.take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    Ok(sniff(&header))
+}
+
+/// Recognises the format from the first bytes of a file.
+pub fn sniff(header: &[u8]) -> Option<FileKind> {
+    let at = |pos: usize, magic: &[u8]| header.get(pos..pos + magic.len()) == Some(magic);
+
+    if at(0, b"\xff\xd8\xff") {
+        Some(FileKind::Jpeg)
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        Some(FileKind::Tiff)
+    } else if at(0, b"IIRO") || at(0, b"IIRS") || at(0, b"MMOR") {
+        Some(FileKind::Orf)
+    } else if at(0, b"IIU\0") {
+        Some(FileKind::Rw2)
+    } else if at(0, b"FUJIFILMCCD-RAW") {
+        Some(FileKind::Raf)
+    } else if at(0, b"\x89PNG\r\n\x1a\n") {
+        Some(FileKind::Png)
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        Some(FileKind::Gif)
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        Some(FileKind::WebP)
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        Some(FileKind::Avi)
+    } else if at(0, b"\x1a\x45\xdf\xa3") {
+        Some(FileKind::Matroska)
+    } else if at(4, b"ftyp") {
+        sniff_ftyp(header)
+    } else if (at(0, &[TS_SYNC]) && at(188, &[TS_SYNC])) || (at(4, &[TS_SYNC]) && at(196, &[TS_SYNC])) {
+        // 188 byte packets, or 192 byte packets with a time code first as in M2TS
+        Some(FileKind::Mts)
+    } else {
+        None
+    }
+}
+
+// The major brand, and for HEIF files the compatible brands, tell what an ISO base media file holds
+fn sniff_ftyp(header: &[u8]) -> Option<FileKind> {
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let major = header.get(8..12)?;
+    let compatible: Vec<&[u8]> = header.get(16..size.min(header.len()))
+        .map(|b| b.chunks_exact(4).collect())
+        .unwrap_or_default();
+
+    match major {
+        b"crx " => Some(FileKind::Cr3),
+        b"avif" | b"avis" => Some(FileKind::Avif),
+        b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" => Some(FileKind::Heic),
+        b"mif1" | b"msf1" if compatible.contains(&&b"avif"[..]) => Some(FileKind::Avif),
+        b"mif1" | b"msf1" => Some(FileKind::Heic),
+        b"qt  " => Some(FileKind::QuickTime),
+        b if b.starts_with(b"3gp") || b.starts_with(b"3g2") => Some(FileKind::ThreeGp),
+        _ => Some(FileKind::Mp4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools;
+
+    #[test]
+    fn test_sniff_file() {
+        let kind = |name: &str| sniff_file(testtools::get_base_dir() + "src/" + name).unwrap();
+        assert_eq!(Some(FileKind::Jpeg), kind("test/gps-date.jpg"));
+        assert_eq!(Some(FileKind::Heic), kind("test/heic/image1.heic"));
+        assert_eq!(Some(FileKind::Mp4), kind("test/creation-time.mp4"));
+        assert_eq!(Some(FileKind::Mp4), kind("test/NO_METADATA.M4V"));
+        assert_eq!(Some(FileKind::Mp4), kind("testsniff/no-creation-time.mp4"));
+        // A WhatsApp photo with the extension of a movie
+        assert_eq!(Some(FileKind::Jpeg), kind("test/subdir/VID-20181129-WA9876.mp4"));
+        assert_eq!(Some(FileKind::QuickTime), kind("test2/FROM_IPHONE.MOV"));
+        assert_eq!(Some(FileKind::Tiff), kind("testraw/nikon.nef"));
+        assert_eq!(Some(FileKind::Orf), kind("testraw/olympus.orf"));
+        assert_eq!(Some(FileKind::Raf), kind("testraw/fujifilm.raf"));
+        assert_eq!(Some(FileKind::Cr3), kind("testraw/canon.cr3"));
+        assert_eq!(Some(FileKind::Avi), kind("testvideo/camera.avi"));
+        assert_eq!(Some(FileKind::Mts), kind("testvideo/camcorder.mts"));
+        assert_eq!(Some(FileKind::Matroska), kind("testvideo/browser.webm"));
+        assert_eq!(Some(FileKind::ThreeGp), kind("testvideo/phone.3gp"));
+        assert_eq!(Some(FileKind::Png), kind("testimage/photo.png"));
+        assert_eq!(Some(FileKind::WebP), kind("testimage/sticker.webp"));
+        assert_eq!(Some(FileKind::Avif), kind("testimage/export.avif"));
+        assert_eq!(Some(FileKind::Tiff), kind("testimage/scan.tiff"));
+        assert_eq!(Some(FileKind::Gif), kind("testimage/animation.gif"));
+        assert_eq!(None, kind("testtools.rs"));
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(Some(FileKind::Png), sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert_eq!(Some(FileKind::Gif), sniff(b"GIF89a"));
+        assert_eq!(Some(FileKind::WebP), sniff(b"RIFF\x10\0\0\0WEBPVP8 "));
+        assert_eq!(Some(FileKind::Avi), sniff(b"RIFF\x10\0\0\0AVI LIST"));
+        assert_eq!(Some(FileKind::Matroska), sniff(b"\x1a\x45\xdf\xa3\x01"));
+        assert_eq!(Some(FileKind::ThreeGp), sniff(b"\0\0\0\x14ftyp3gp4\0\0\0\0isom"));
+        assert_eq!(Some(FileKind::Avif), sniff(b"\0\0\0\x1cftypmif1\0\0\0\0mif1avifmiaf"));
+        assert_eq!(None, sniff(b"\0\0\0\x14fty"));
+        assert_eq!(None, sniff(b""));
+    }
+
+    #[test]
+    fn test_detect() {
+        let dir = testtools::get_target_dir() + "test_detect";
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let photo = Path::new(&dir).join("photo.mp4");
+        std::fs::copy(testtools::get_base_dir() + "src/test/gps-date.jpg", &photo).unwrap();
+        assert_eq!(Some(FileKind::Jpeg), detect(&photo).unwrap());
+        let text = Path::new(&dir).join("text.mov");
+        std::fs::write(&text, "not a movie").unwrap();
+        assert_eq!(Some(FileKind::QuickTime), detect(&text).unwrap());
+        assert!(detect(&Path::new(&dir).join("missing.jpg")).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(FileKind::QuickTime.matches(FileKind::Mp4));
+        assert!(FileKind::Tiff.matches(FileKind::from_extension("dng").unwrap()));
+        assert!(!FileKind::Jpeg.matches(FileKind::Mp4));
+    }
+}
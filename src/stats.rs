@@ -0,0 +1,86 @@
+use crate::copier::DateSource;
+use crate::link::LinkMode;
+
+use chrono::NaiveDateTime;
+use std::path::PathBuf;
+
+/// A file that would be copied, as planned in a dry run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedCopy {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// The local date and time that decided the target
+    pub local_time: NaiveDateTime,
+    pub date_source: DateSource,
+    /// Whether the date was inferred, from the file name or modification time, rather than read
+    /// from the metadata
+    pub date_inferred: bool,
+    /// The number of the _001 style suffix added because of a name collision, if any
+    pub suffix: Option<u32>,
+    /// Whether EXIF dates would be written to the copy
+    pub write_exif: bool,
+    /// Whether the file has EXIF data already, which would be updated rather than added
+    pub has_exif: bool,
+    pub link_mode: LinkMode,
+    /// Whether the source would be removed, in move mode
+    pub remove_source: bool
+}
+
+/// What happened during a copy, returned by Copier::copy().
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CopyStats {
+    /// Files found in the source directory tree
+    pub scanned: u64,
+    /// Files copied, or that would be copied in a dry run
+    pub copied: u64,
+    pub skipped_hidden: u64,
+    /// Files left out by the include and exclude patterns
+    pub skipped_excluded: u64,
+    /// Files with a capture time outside the date range
+    pub skipped_out_of_range: u64,
+    /// Files smaller than the minimum size
+    pub skipped_too_small: u64,
+    /// Files that are not a supported photo or video
+    pub skipped_unsupported: u64,
+    /// Files for which an identical file was found at the target location
+    pub skipped_identical: u64,
+    /// Files that the import index shows were imported before
+    pub skipped_already_imported: u64,
+    /// Copied files that got another name because a different file had the same name
+    pub renamed: u64,
+    pub dates_from_metadata: u64,
+    pub dates_inferred: u64,
+    /// Copied files that had an EXIF date written to them
+    pub exif_written: u64,
+    /// Source files removed in move mode
+    pub sources_removed: u64,
+    /// Files and directories that could not be processed
+    pub errors: u64,
+    /// The path and error message for each error
+    pub failures: Vec<(PathBuf, String)>,
+    /// What would be copied where, in a dry run
+    pub planned: Vec<PlannedCopy>
+}
+
+impl CopyStats {
+    /// The counters with a description, in the order they are best reported.
+    pub fn rows(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("Files scanned", self.scanned),
+            ("Copied", self.copied),
+            ("Skipped: hidden", self.skipped_hidden),
+            ("Skipped: excluded", self.skipped_excluded),
+            ("Skipped: outside date range", self.skipped_out_of_range),
+            ("Skipped: below minimum size", self.skipped_too_small),
+            ("Skipped: unsupported type", self.skipped_unsupported),
+            ("Skipped: identical file exists", self.skipped_identical),
+            ("Skipped: imported before", self.skipped_already_imported),
+            ("Renamed due to name collision", self.renamed),
+            ("Dates from metadata", self.dates_from_metadata),
+            ("Dates inferred", self.dates_inferred),
+            ("EXIF dates written", self.exif_written),
+            ("Sources removed", self.sources_removed),
+            ("Errors", self.errors)
+        ]
+    }
+}
@@ -0,0 +1,305 @@
+use crate::error::{Error, Result};
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use std::path::PathBuf;
+
+/// The layout used when none is specified: dest/2019/2019-04-27/
+pub const DEFAULT_LAYOUT: &str = "{YYYY}/{YYYY}-{MM}-{DD}";
+
+const UNKNOWN: &str = "Unknown";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Year,
+    ShortYear,
+    Month,
+    Day,
+    Quarter,
+    Hour,
+    Minute,
+    Second,
+    Make,
+    Model,
+    MediaType,
+    Ext,
+    Date,
+    Time,
+    Camera,
+    Name,
+    Seq
+}
+
+impl Token {
+    fn from_name(name: &str) -> Option<Token> {
+        let token = match name {
+            "YYYY" => Token::Year,
+            "YY" => Token::ShortYear,
+            "MM" => Token::Month,
+            "DD" => Token::Day,
+            "Q" => Token::Quarter,
+            "hh" => Token::Hour,
+            "mm" => Token::Minute,
+            "ss" => Token::Second,
+            "make" => Token::Make,
+            "model" => Token::Model,
+            "type" => Token::MediaType,
+            "ext" => Token::Ext,
+            "date" => Token::Date,
+            "time" => Token::Time,
+            "camera" => Token::Camera,
+            "name" => Token::Name,
+            "seq" => Token::Seq,
+            _ => return None
+        };
+        Some(token)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Token(Token)
+}
+
+/// The values a template is rendered with.
+#[derive(Debug)]
+pub struct TemplateValues<'a> {
+    pub date_time: NaiveDateTime,
+    pub make: Option<&'a str>,
+    pub model: Option<&'a str>,
+    /// "photo" or "video"
+    pub media_type: &'a str,
+    /// The original file extension
+    pub ext: &'a str,
+    /// The original file name without extension
+    pub name: &'a str,
+    /// The sequence number used to make file names unique
+    pub seq: u32
+}
+
+/// A destination folder layout such as `{YYYY}/{MM}/{DD}` or `{model}/{YYYY}-{MM}-{DD}`, or a file
+/// name template such as `{date}_{time}_{name}.{ext}`.
+///
+/// Supported tokens are the date tokens `{YYYY}`, `{YY}`, `{MM}`, `{DD}`, `{Q}` (quarter, 1-4),
+/// `{date}` (YYYYMMDD), the time tokens `{hh}`, `{mm}`, `{ss}`, `{time}` (hhmmss) and the metadata
+/// tokens `{make}` and `{model}` (of the camera), `{camera}` (the model, or the make if the model
+/// is not known), `{type}` (photo or video), `{name}` (the original file name without extension)
+/// and `{ext}` (the original file extension in lower case). File name templates can also use `{seq}`,
+/// a three digit sequence number that is increased to make the name unique.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            match rest.find('{') {
+                Some(0) => {
+                    let end = rest.find('}').ok_or_else(|| invalid_template(template, "missing '}'"))?;
+                    let name = &rest[1..end];
+                    let token = Token::from_name(name)
+                        .ok_or_else(|| invalid_template(template, &format!("unknown token {{{}}}", name)))?;
+                    parts.push(Part::Token(token));
+                    rest = &rest[end + 1..];
+                },
+                Some(idx) => {
+                    parts.push(Part::Literal(rest[..idx].to_string()));
+                    rest = &rest[idx..];
+                },
+                None => {
+                    parts.push(Part::Literal(rest.to_string()));
+                    rest = "";
+                }
+            }
+        }
+
+        if parts.iter().any(|p| matches!(p, Part::Literal(l) if l.contains('}'))) {
+            return Err(invalid_template(template, "unexpected '}'"));
+        }
+        if parts.is_empty() {
+            return Err(invalid_template(template, "template is empty"));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Parses a directory layout template, which cannot use `{seq}`.
+    pub fn parse_layout(template: &str) -> Result<Template> {
+        let t = Template::parse(template)?;
+        if t.uses_seq() {
+            return Err(invalid_template(template, "{seq} can only be used for file names"));
+        }
+        Ok(t)
+    }
+
+    /// Parses a file name template, which cannot contain directories.
+    pub fn parse_file_name(template: &str) -> Result<Template> {
+        let t = Template::parse(template)?;
+        if t.parts.iter().any(|p| matches!(p, Part::Literal(l) if l.contains('/') || l.contains('\\'))) {
+            return Err(invalid_template(template, "file names cannot contain directories"));
+        }
+        Ok(t)
+    }
+
+    pub fn default_layout() -> Template {
+        Template::parse_layout(DEFAULT_LAYOUT).unwrap()
+    }
+
+    /// Whether rendering needs the camera make or model, which takes extra work to obtain.
+    pub fn uses_camera(&self) -> bool {
+        self.parts.iter().any(|p| matches!(p,
+            Part::Token(Token::Make) | Part::Token(Token::Model) | Part::Token(Token::Camera)))
+    }
+
+    /// Whether the template has a `{seq}` token, which makes an extra `_001` style suffix unnecessary.
+    pub fn uses_seq(&self) -> bool {
+        self.parts.contains(&Part::Token(Token::Seq))
+    }
+
+    pub fn render(&self, values: &TemplateValues) -> String {
+        let dt = &values.date_time;
+        let mut res = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(l) => res.push_str(l),
+                Part::Token(t) => {
+                    let v = match t {
+                        Token::Year => format!("{:04}", dt.year()),
+                        Token::ShortYear => format!("{:02}", dt.year() % 100),
+                        Token::Month => format!("{:02}", dt.month()),
+                        Token::Day => format!("{:02}", dt.day()),
+                        Token::Quarter => format!("{}", (dt.month() - 1) / 3 + 1),
+                        Token::Hour => format!("{:02}", dt.hour()),
+                        Token::Minute => format!("{:02}", dt.minute()),
+                        Token::Second => format!("{:02}", dt.second()),
+                        Token::Make => sanitize(values.make),
+                        Token::Model => sanitize(values.model),
+                        Token::MediaType => sanitize(Some(values.media_type)),
+                        Token::Ext => sanitize(Some(values.ext)).to_lowercase(),
+                        Token::Date => dt.format("%Y%m%d").to_string(),
+                        Token::Time => dt.format("%H%M%S").to_string(),
+                        Token::Camera => sanitize(values.model.or(values.make)),
+                        Token::Name => sanitize(Some(values.name)),
+                        Token::Seq => format!("{:03}", values.seq)
+                    };
+                    res.push_str(&v);
+                }
+            }
+        }
+        res
+    }
+
+    /// Renders the template as a relative directory path. Each `/` separated part becomes a directory.
+    pub fn render_dir(&self, values: &TemplateValues) -> PathBuf {
+        self.render(values)
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .map(|c| if c == "." || c == ".." { "_" } else { c })
+            .collect()
+    }
+
+    /// Renders the template as a file name. Leading dots are left out, so that values can't make it
+    /// `.`, `..` or a hidden file such as the import index, and an empty name becomes `_`.
+    pub fn render_file_name(&self, values: &TemplateValues) -> String {
+        let name = self.render(values);
+        match name.trim_start_matches('.') {
+            "" => "_".to_string(),
+            n => n.to_string()
+        }
+    }
+}
+
+// Makes a metadata value usable as (part of) a file name
+fn sanitize(value: Option<&str>) -> String {
+    let v: String = value.unwrap_or("")
+        .trim()
+        .chars()
+        .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect();
+    if v.is_empty() {
+        UNKNOWN.to_string()
+    } else {
+        v
+    }
+}
+
+fn invalid_template(template: &str, msg: &str) -> Error {
+    Error::InvalidInput(format!("Invalid template '{}': {}", template, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values<'a>() -> TemplateValues<'a> {
+        TemplateValues {
+            date_time: NaiveDateTime::parse_from_str("2019-04-27 14:08:01", "%Y-%m-%d %H:%M:%S").unwrap(),
+            make: Some("Sony"),
+            model: Some("H3113"),
+            media_type: "photo",
+            ext: "JPG",
+            name: "gps-date",
+            seq: 1
+        }
+    }
+
+    #[test]
+    fn test_default_layout() {
+        assert_eq!(PathBuf::from("2019/2019-04-27"), Template::default_layout().render_dir(&values()));
+        assert!(!Template::default_layout().uses_camera());
+    }
+
+    #[test]
+    fn test_layouts() {
+        let v = values();
+        assert_eq!("2019/04/27", Template::parse("{YYYY}/{MM}/{DD}").unwrap().render(&v));
+        assert_eq!("2019/2019-04", Template::parse("{YYYY}/{YYYY}-{MM}").unwrap().render(&v));
+        assert_eq!("2019/Q2", Template::parse("{YYYY}/Q{Q}").unwrap().render(&v));
+        assert_eq!("19-04-27 140801", Template::parse("{YY}-{MM}-{DD} {hh}{mm}{ss}").unwrap().render(&v));
+        assert_eq!("Sony H3113/2019-04-27/photo/jpg",
+            Template::parse("{make} {model}/{YYYY}-{MM}-{DD}/{type}/{ext}").unwrap().render(&v));
+        assert!(Template::parse("{model}/{YYYY}").unwrap().uses_camera());
+    }
+
+    #[test]
+    fn test_file_names() {
+        let v = values();
+        assert_eq!("20190427_140801_gps-date.jpg", Template::parse_file_name("{date}_{time}_{name}.{ext}").unwrap().render(&v));
+        let t = Template::parse_file_name("{date}_{time}_{camera}_{seq}.{ext}").unwrap();
+        assert_eq!("20190427_140801_H3113_001.jpg", t.render(&v));
+        assert_eq!("20190427_140801_Sony_012.jpg", t.render(&TemplateValues { model: None, seq: 12, ..values() }));
+        assert!(t.uses_seq());
+        assert!(t.uses_camera());
+        assert!(Template::parse_file_name("{YYYY}/{name}.{ext}").is_err());
+        assert!(Template::parse_layout("{YYYY}/{seq}").is_err());
+    }
+
+    #[test]
+    fn test_unsafe_values() {
+        let v = TemplateValues { make: None, model: Some("../A/B\n"), ..values() };
+        let t = Template::parse("{make}/{model}").unwrap();
+        assert_eq!("Unknown/.._A_B", t.render(&v));
+
+        let v = TemplateValues { model: Some(".."), ..values() };
+        let t = Template::parse("x//{model}/{YYYY}/").unwrap();
+        assert_eq!(PathBuf::from("x/_/2019"), t.render_dir(&v));
+
+        let t = Template::parse_file_name("{model}").unwrap();
+        assert_eq!("_", t.render_file_name(&v));
+        assert_eq!("_", t.render_file_name(&TemplateValues { model: Some("."), ..values() }));
+        assert_eq!("H3113", t.render_file_name(&values()));
+        let t = Template::parse_file_name("{name}.{ext}").unwrap();
+        assert_eq!("phototools-index.jpg", t.render_file_name(&TemplateValues { name: ".phototools-index", ..values() }));
+        assert_eq!("jpg", t.render_file_name(&TemplateValues { name: ".", ..values() }));
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(Template::parse("{YYYY}/{month}").unwrap_err().to_string().contains("unknown token {month}"));
+        assert!(Template::parse("{YYYY").is_err());
+        assert!(Template::parse("YYYY}").is_err());
+        assert!(Template::parse("").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::error::{Error, Result};
+
+use chrono::{DateTime, FixedOffset, Local, LocalResult, NaiveDateTime, TimeZone, Utc};
+use std::fmt;
+
+/// What is known about the time zone of a timestamp.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Zone {
+    /// A local time without any time zone information, e.g. EXIF DateTimeOriginal on older cameras
+    Floating,
+    /// A UTC time, e.g. from GPS or an MP4 movie header. The local time at the place of capture is not known.
+    Utc,
+    /// A local time with its offset from UTC, e.g. DateTimeOriginal with OffsetTimeOriginal
+    Offset(FixedOffset)
+}
+
+/// The moment a photo or video was taken, as recorded in its metadata.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamp {
+    /// The date and time as recorded, in the zone below
+    pub date_time: NaiveDateTime,
+    pub zone: Zone
+}
+
+/// Which local time is used for folders, file names and the EXIF dates written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimezoneMode {
+    /// The local time at the place of capture when it is known, otherwise the time as recorded, so
+    /// UTC times such as those of videos and file modification times stay in UTC
+    #[default]
+    Recorded,
+    /// Everything with a known zone is converted to the time zone of this computer
+    Local,
+    /// Everything with a known zone is converted to UTC
+    Utc,
+    /// Everything with a known zone is converted to this offset
+    Fixed(FixedOffset)
+}
+
+impl TimezoneMode {
+    /// Parses `recorded`, `local`, `utc` or an offset such as `+02:00` or `-0530`.
+    pub fn parse(s: &str) -> Result<TimezoneMode> {
+        match s.to_lowercase().as_str() {
+            "recorded" => Ok(TimezoneMode::Recorded),
+            "local" => Ok(TimezoneMode::Local),
+            "utc" | "z" => Ok(TimezoneMode::Utc),
+            _ => parse_offset(s)
+                .map(TimezoneMode::Fixed)
+                .ok_or_else(|| Error::InvalidInput(format!(
+                    "Invalid time zone '{}': use recorded, local, utc or an offset like +02:00", s)))
+        }
+    }
+}
+
+impl Timestamp {
+    pub fn floating(date_time: NaiveDateTime) -> Timestamp {
+        Timestamp { date_time, zone: Zone::Floating }
+    }
+
+    pub fn utc(date_time: NaiveDateTime) -> Timestamp {
+        Timestamp { date_time, zone: Zone::Utc }
+    }
+
+    pub fn with_offset(date_time: NaiveDateTime, offset: FixedOffset) -> Timestamp {
+        Timestamp { date_time, zone: Zone::Offset(offset) }
+    }
+
+    /// Parses `YYYY-MM-DD hh:mm:ss` without zone.
+    pub fn parse_floating(s: &str) -> Option<Timestamp> {
+        NaiveDateTime::parse_from_str(s.trim(), "%Y-%m-%d %H:%M:%S").ok().map(Timestamp::floating)
+    }
+
+    /// The moment in UTC, if the zone is known.
+    pub fn to_utc(&self) -> Option<DateTime<Utc>> {
+        match self.zone {
+            Zone::Floating => None,
+            Zone::Utc => Some(self.date_time.and_utc()),
+            Zone::Offset(o) => Some((self.date_time - o).and_utc())
+        }
+    }
+
+    /// The local date and time to file this under. Floating times are always used as they are.
+    pub fn local_time(&self, mode: TimezoneMode) -> NaiveDateTime {
+        let utc = match self.to_utc() {
+            Some(utc) => utc,
+            None => return self.date_time
+        };
+        match (mode, self.zone) {
+            (TimezoneMode::Recorded, Zone::Offset(_)) => self.date_time,
+            (TimezoneMode::Local, _) => utc.with_timezone(&Local).naive_local(),
+            (TimezoneMode::Recorded, _) | (TimezoneMode::Utc, _) => utc.naive_utc(),
+            (TimezoneMode::Fixed(o), _) => utc.with_timezone(&o).naive_local()
+        }
+    }
+
+    /// The moment to use for file times. A floating time is taken to be in the zone the mode
+    /// converts to: UTC when recorded, the local time zone of this computer when local.
+    pub fn file_time(&self, mode: TimezoneMode) -> DateTime<Utc> {
+        if let Some(utc) = self.to_utc() {
+            return utc;
+        }
+        let local = match mode {
+            TimezoneMode::Recorded | TimezoneMode::Utc => return self.date_time.and_utc(),
+            TimezoneMode::Fixed(o) => o.from_local_datetime(&self.date_time).map(|d| d.with_timezone(&Utc)),
+            TimezoneMode::Local => Local.from_local_datetime(&self.date_time).map(|d| d.with_timezone(&Utc))
+        };
+        match local {
+            LocalResult::Single(d) | LocalResult::Ambiguous(d, _) => d,
+            // In a DST gap
+            LocalResult::None => self.date_time.and_utc()
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    /// ISO 8601, e.g. `2019-04-27T14:08:01+02:00`, with `Z` for UTC and without zone when floating.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.date_time.format("%Y-%m-%dT%H:%M:%S"))?;
+        match self.zone {
+            Zone::Floating => Ok(()),
+            Zone::Utc => write!(f, "Z"),
+            Zone::Offset(o) => write!(f, "{}", o)
+        }
+    }
+}
+
+/// Parses a UTC offset such as `+02:00`, `-0530` or `+01`, as used by EXIF OffsetTime and QuickTime.
+pub fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let s = s.trim();
+    let sign = match s.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None
+    };
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse::<i32>().ok()?),
+        _ => return None
+    };
+    if minutes >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_parse_offset() {
+        assert_eq!(FixedOffset::east_opt(7200), parse_offset("+02:00"));
+        assert_eq!(FixedOffset::east_opt(-(5 * 3600 + 1800)), parse_offset("-0530"));
+        assert_eq!(FixedOffset::east_opt(3600), parse_offset("+01"));
+        assert_eq!(None, parse_offset("02:00"));
+        assert_eq!(None, parse_offset("+2:00"));
+        assert_eq!(None, parse_offset("+02:75"));
+        assert_eq!(None, parse_offset("   :  "));
+    }
+
+    #[test]
+    fn test_timezone_mode() {
+        assert_eq!(TimezoneMode::Recorded, TimezoneMode::parse("recorded").unwrap());
+        assert_eq!(TimezoneMode::Utc, TimezoneMode::parse("UTC").unwrap());
+        assert_eq!(TimezoneMode::Fixed(FixedOffset::east_opt(-3600).unwrap()), TimezoneMode::parse("-01:00").unwrap());
+        assert!(TimezoneMode::parse("Europe/Amsterdam").is_err());
+    }
+
+    #[test]
+    fn test_local_time() {
+        let plus2 = FixedOffset::east_opt(7200).unwrap();
+        // Shortly after midnight local time, still the previous day in UTC
+        let ts = Timestamp::with_offset(dt("2019-04-28 00:30:00"), plus2);
+        assert_eq!(dt("2019-04-28 00:30:00"), ts.local_time(TimezoneMode::Recorded));
+        assert_eq!(dt("2019-04-27 22:30:00"), ts.local_time(TimezoneMode::Utc));
+        assert_eq!(dt("2019-04-27 23:30:00"), ts.local_time(TimezoneMode::Fixed(FixedOffset::east_opt(3600).unwrap())));
+        assert_eq!(dt("2019-04-27 22:30:00"), ts.to_utc().unwrap().naive_utc());
+
+        let ts = Timestamp::utc(dt("2019-04-27 22:30:00"));
+        assert_eq!(dt("2019-04-28 00:30:00"), ts.local_time(TimezoneMode::Fixed(plus2)));
+        assert_eq!(dt("2019-04-27 22:30:00"), ts.local_time(TimezoneMode::Utc));
+
+        // Floating times don't change
+        let ts = Timestamp::floating(dt("2019-04-28 00:30:00"));
+        assert_eq!(dt("2019-04-28 00:30:00"), ts.local_time(TimezoneMode::Utc));
+        assert_eq!(dt("2019-04-27 22:30:00"), ts.file_time(TimezoneMode::Fixed(plus2)).naive_utc());
+    }
+
+    #[test]
+    fn test_default_mode() {
+        // Like before there was a choice: only times with an offset are local, everything else is UTC
+        let mode = TimezoneMode::default();
+        assert_eq!(TimezoneMode::Recorded, mode);
+        let ts = Timestamp::utc(dt("2019-04-27 22:30:00"));
+        assert_eq!(dt("2019-04-27 22:30:00"), ts.local_time(mode));
+        assert_eq!(dt("2019-04-27 22:30:00"), ts.file_time(mode).naive_utc());
+        let ts = Timestamp::floating(dt("2019-04-28 00:30:00"));
+        assert_eq!(dt("2019-04-28 00:30:00"), ts.local_time(mode));
+        assert_eq!(dt("2019-04-28 00:30:00"), ts.file_time(mode).naive_utc());
+        let ts = Timestamp::with_offset(dt("2019-04-28 00:30:00"), FixedOffset::east_opt(7200).unwrap());
+        assert_eq!(dt("2019-04-28 00:30:00"), ts.local_time(mode));
+        assert_eq!(dt("2019-04-27 22:30:00"), ts.file_time(mode).naive_utc());
+    }
+
+    #[test]
+    fn test_display() {
+        let t = dt("2019-04-27 14:08:01");
+        assert_eq!("2019-04-27T14:08:01", Timestamp::floating(t).to_string());
+        assert_eq!("2019-04-27T14:08:01Z", Timestamp::utc(t).to_string());
+        assert_eq!("2019-04-27T14:08:01-05:30",
+            Timestamp::with_offset(t, FixedOffset::west_opt(5 * 3600 + 1800).unwrap()).to_string());
+    }
+}
@@ -1,109 +1,108 @@
-use crate::copier::DateResult;
+use crate::bmff::{self, Mp4Metadata};
+use crate::copier::{DateResult, DateSource};
+use crate::error::{Error, Result};
+use crate::filename_dates::FilenameDates;
 use crate::filetools;
+use crate::matroska;
+use crate::mts;
+use crate::riff;
+use crate::sniff::{self, FileKind};
+use crate::timestamp::{self, Timestamp};
 
+use chrono::NaiveDateTime;
 use log::debug;
+use std::fs;
 use std::io;
 use std::path::Path;
-use std::process::Command;
-use std::string::FromUtf8Error;
 use regex::Regex;
 
 pub struct VideoHandler {
-    pattern: Regex,
-    quicktime_pattern: Regex
+    pattern: Regex
 }
 
 impl VideoHandler {
     pub fn new() -> VideoHandler {
         VideoHandler {
-            pattern: Regex::new(
-                r"creation_time\s+[:]\s+(\d\d\d\d-\d\d-\d\d)T(\d\d:\d\d:\d\d)[.]\d+Z").unwrap(),
-            quicktime_pattern: Regex::new(
-                r"com.apple.quicktime.creationdate[:]\s+(\d\d\d\d-\d\d-\d\d)T(\d\d:\d\d:\d\d)+").unwrap()
+            pattern: Regex::new(r"^(\d\d\d\d-\d\d-\d\d)T(\d\d:\d\d:\d\d)(?:\.\d+)?(Z|[+-]\d\d(?::?\d\d)?)?").unwrap()
         }
     }
 
-    pub fn get_date_time<P: AsRef<Path>>(&self, p: P) -> io::Result<DateResult> {
-        let output = VideoHandler::get_ffmpeg_output(p.as_ref());
+    pub fn get_date_time<P: AsRef<Path>>(&self, p: P) -> Result<DateResult> {
+        self.get_date_time_using(p, FilenameDates::builtin())
+    }
 
-        if let Ok(ffmpeg_output) = output {
-            if let Some(ct) = self.get_creationtime_from_string(ffmpeg_output) {
-                return Ok(DateResult::FromMetadata(ct));
-            }
+    /// The date and time of the video, using these patterns for files without date metadata.
+    pub fn get_date_time_using<P: AsRef<Path>>(&self, p: P, filename_dates: &FilenameDates) -> Result<DateResult> {
+        match self.get_recording_time(p.as_ref()) {
+            Ok(Some((ts, src))) => return Ok(DateResult::FromMetadata(ts, src)),
+            Ok(None) => {},
+            Err(e) => debug!("Unable to read video metadata from {:?}: {}", p.as_ref(), e)
         }
 
-        if let Some(d) = VideoHandler::get_whatsapp_filename_date(p.as_ref()) {
-            return Ok(DateResult::Inferred(d));
+        if let Some((d, _)) = p.as_ref().file_name().and_then(|n| filename_dates.date_time(&n.to_string_lossy())) {
+            return Ok(DateResult::Inferred(d, DateSource::Filename));
         }
 
-        let res = filetools::get_time_from_file(p.as_ref());
-        match res {
-            Ok(r) => Ok(DateResult::Inferred(r)),
-            Err(e) => Err(e)
-        }
+        let modified = fs::metadata(p.as_ref())
+            .and_then(|md| filetools::get_modified_time(&md))
+            .map_err(|e| Error::io(p.as_ref(), e))?;
+        Ok(DateResult::Inferred(Timestamp::utc(modified), DateSource::FileModified))
     }
 
-    // TODO share with image via filetools?
-    fn get_whatsapp_filename_date(path: &Path) -> Option<String> {
-        let p = Regex::new(r"VID-(\d{8})-WA\d{4}.mp4").unwrap(); // TODO make constant
-        let f = &path.file_name().unwrap().to_string_lossy();
-        let res = p.captures(f);
-        if let Some(x) = res {
-            let ds = x[1].to_string();
-            debug!("Found a whatsapp file date! {:?}", ds);
-            // TODO remove the dummy time of 1300 hrs
-            let d = format!("{}-{}-{} 13:00:00", &ds[0..4], &ds[4..6], &ds[6..8]);
-            return Some(d)
+    /// The make and model of the recording device, if known.
+    pub fn get_camera<P: AsRef<Path>>(&self, p: P) -> (Option<String>, Option<String>) {
+        match bmff::read_metadata_from_file(p.as_ref()) {
+            Ok(md) => (md.make, md.model),
+            Err(_) => (None, None)
         }
-        None
     }
 
-    fn get_creationtime_from_string(&self, s: String) -> Option<String> {
-        // First let's see if there is quicktime creationdate information, as on IPhone-recorded movies that is 
-        // more reliable than the 'creation_time' attribute...
-        for line in s.lines() {
-            if line.trim().starts_with("com.apple.quicktime.creationdate") {
-                return Some(self.parse_quicktime_creation_date(line.to_string()));
-            }
-        }
-        
-        for line in s.lines() {
-            if line.trim().starts_with("creation_time") {
-                return Some(self.parse_creation_time(line.to_string()));
-            }
+    // The recording time from the metadata of the container, which is recognised by its content
+    fn get_recording_time(&self, p: &Path) -> io::Result<Option<(Timestamp, DateSource)>> {
+        let kind = sniff::sniff_file(p)?.or_else(|| FileKind::from_path(p));
+        match kind {
+            Some(FileKind::Avi) => Ok(riff::read_avi_date(p)?
+                .and_then(|d| VideoHandler::parse_avi_date(&d))
+                .map(|dt| (Timestamp::floating(dt), DateSource::AviDate))),
+            Some(FileKind::Mts) => Ok(mts::read_recording_time(p)?
+                .map(|dt| (Timestamp::floating(dt), DateSource::AvchdDate))),
+            Some(FileKind::Matroska) => Ok(matroska::read_date_utc_from_file(p)?
+                .map(|dt| (Timestamp::utc(dt), DateSource::MatroskaDate))),
+            _ => Ok(self.get_creationtime_from_metadata(bmff::read_metadata_from_file(p)?))
         }
-        
-        None
     }
 
-    fn parse_quicktime_creation_date(&self, s: String) -> String {
-        if let Some(q) = self.quicktime_pattern.captures(s.as_str()) {
-            let res = q[1].to_string() + " " + &q[2].to_string();
-            res
-        } else {
-            "".to_string()
-        }
+    // Parses e.g. FRI JAN 01 12:00:00 2010 or 2010:01:01 12:00:00, a local time
+    fn parse_avi_date(s: &str) -> Option<NaiveDateTime> {
+        // ctime pads the day with a space
+        let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+        ["%a %b %e %H:%M:%S %Y", "%Y:%m:%d %H:%M:%S", "%Y/%m/%d %H:%M:%S", "%Y-%m-%d %H:%M:%S"].iter()
+            .find_map(|f| NaiveDateTime::parse_from_str(&s, f).ok())
     }
 
-    fn parse_creation_time(&self, s: String) -> String {
-        let x = self.pattern.captures(s.as_str());
-        if let None = x {
-            "".to_string()
-        } else {
-            let cap = x.unwrap();
-            let res = cap[1].to_string() + " " + &cap[2].to_string();
-            res
+    fn get_creationtime_from_metadata(&self, md: Mp4Metadata) -> Option<(Timestamp, DateSource)> {
+        // First let's see if there is quicktime creationdate information, as on IPhone-recorded movies that is
+        // more reliable than the 'creation_time' attribute...
+        if let Some(ts) = md.quicktime_creation_date.and_then(|qt| self.parse_iso_date_time(&qt)) {
+            return Some((ts, DateSource::QuickTime));
         }
+
+        if let Some(ts) = md.day.and_then(|day| self.parse_iso_date_time(&day)) {
+            return Some((ts, DateSource::UserDataDate));
+        }
+
+        md.creation_time.map(|ct| (Timestamp::utc(ct), DateSource::CreationTime))
     }
 
-    fn get_ffmpeg_output(p: &Path) -> Result<String, FromUtf8Error> {
-        let cmd = Command::new("ffmpeg")
-            .arg("-i")
-            .arg(p.to_str().unwrap())
-            .arg("-dump")
-            .output()
-            .expect("Failed to execute ffmpeg, is it installed?");
-        String::from_utf8(cmd.stderr)
+    // Parses e.g. 2018-06-02T11:12:13+0100, 2019-05-01T17:40:16.000000Z or 2019-05-01T17:40:16
+    fn parse_iso_date_time(&self, s: &str) -> Option<Timestamp> {
+        let c = self.pattern.captures(s.trim())?;
+        let ts = Timestamp::parse_floating(&format!("{} {}", &c[1], &c[2]))?;
+        match c.get(3).map(|z| z.as_str()) {
+            Some("Z") => Some(Timestamp::utc(ts.date_time)),
+            Some(offset) => timestamp::parse_offset(offset).map(|o| Timestamp::with_offset(ts.date_time, o)),
+            None => Some(ts)
+        }
     }
 }
 
@@ -111,28 +110,79 @@ impl VideoHandler {
 mod tests {
     use super::*;
     use crate::testtools;
-    use chrono::DateTime;
+    use crate::timestamp::Zone;
+    use chrono::{DateTime, NaiveDateTime};
     use chrono::offset::Utc;
-    use std::fs;
-    use std::io;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
 
     #[test]
     fn test_video_date_time_metadata() {
         let s = testtools::get_base_dir() + "src/test/creation-time.mp4";
         let p1 = Path::new(s.as_str());
-        assert_eq!(DateResult::FromMetadata(String::from("2019-05-01 17:40:16")),
+        assert_eq!(DateResult::FromMetadata(Timestamp::utc(dt("2019-05-01 17:40:16")), DateSource::CreationTime),
             VideoHandler::new().get_date_time(p1).unwrap());
     }
 
     #[test]
-    fn test_video_date_time_file() -> io::Result<()> {
+    fn test_video_date_time_file() -> Result<()> {
         let filename = testtools::get_base_dir() + "src/test/NO_METADATA.M4V";
         let p1 = Path::new(filename.as_str());
         let md = fs::metadata(&filename)?;
         let created: DateTime<Utc> = DateTime::from(md.created()?);
-        let expected = format!("{}", created.format("%Y-%m-%d %T"));
-        assert_eq!(DateResult::Inferred(expected), 
+        let expected = Timestamp::utc(dt(&created.format("%Y-%m-%d %T").to_string()));
+        assert_eq!(DateResult::Inferred(expected, DateSource::FileModified),
             VideoHandler::new().get_date_time(p1)?);
         Ok(())
     }
+
+    #[test]
+    fn test_video_quicktime_creation_date() {
+        let s = testtools::get_base_dir() + "src/test2/FROM_IPHONE.MOV";
+        let r = VideoHandler::new().get_date_time(Path::new(&s)).unwrap();
+        match r {
+            DateResult::FromMetadata(ts, src) => {
+                assert!(ts.to_string().starts_with("2018-06-02T"), "Unexpected: {}", ts);
+                assert!(matches!(ts.zone, Zone::Offset(_)), "Should have the offset: {}", ts);
+                assert_eq!(DateSource::QuickTime, src);
+            },
+            DateResult::Inferred(..) => panic!("Should have found the quicktime creation date")
+        }
+    }
+
+    #[test]
+    fn test_video_containers() {
+        let vh = VideoHandler::new();
+        let date_time = |name: &str| vh.get_date_time(testtools::get_base_dir() + "src/testvideo/" + name).unwrap();
+        assert_eq!(DateResult::FromMetadata(Timestamp::floating(dt("2010-03-02 09:15:30")), DateSource::AviDate),
+            date_time("camera.avi"));
+        assert_eq!(DateResult::FromMetadata(Timestamp::floating(dt("2012-07-14 18:30:05")), DateSource::AvchdDate),
+            date_time("camcorder.mts"));
+        assert_eq!(DateResult::FromMetadata(Timestamp::utc(dt("2022-11-05 20:45:10")), DateSource::MatroskaDate),
+            date_time("screen.mkv"));
+        assert_eq!(DateResult::FromMetadata(Timestamp::utc(dt("2009-08-20 10:11:12")), DateSource::CreationTime),
+            date_time("phone.3gp"));
+        assert!(matches!(date_time("browser.webm"), DateResult::Inferred(_, DateSource::FileModified)));
+    }
+
+    #[test]
+    fn test_parse_avi_date() {
+        assert_eq!(Some(dt("2010-01-01 12:00:00")), VideoHandler::parse_avi_date("FRI JAN  1 12:00:00 2010"));
+        assert_eq!(Some(dt("2010-01-01 12:00:00")), VideoHandler::parse_avi_date("Fri Jan 01 12:00:00 2010"));
+        assert_eq!(Some(dt("2010-01-01 12:00:00")), VideoHandler::parse_avi_date("2010:01:01 12:00:00"));
+        assert_eq!(None, VideoHandler::parse_avi_date("yesterday"));
+    }
+
+    #[test]
+    fn test_parse_iso_date_time() {
+        let vh = VideoHandler::new();
+        let iso = |s: &str| vh.parse_iso_date_time(s).map(|ts| ts.to_string());
+        assert_eq!(Some("2018-06-02T11:12:13+01:00".to_string()), iso("2018-06-02T11:12:13+0100"));
+        assert_eq!(Some("2018-06-02T11:12:13-07:00".to_string()), iso("2018-06-02T11:12:13-07:00"));
+        assert_eq!(Some("2019-05-01T17:40:16Z".to_string()), iso("2019-05-01T17:40:16.000000Z"));
+        assert_eq!(Some("2019-05-01T17:40:16".to_string()), iso("2019-05-01T17:40:16"));
+        assert_eq!(None, iso("2019"));
+    }
 }
\ No newline at end of file
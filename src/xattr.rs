@@ -0,0 +1,119 @@
+use std::io;
+use std::path::Path;
+
+/// The extended attribute that keeps the modification time a file had before it was copied, as
+/// an RFC 3339 UTC time such as `2023-05-01T10:00:00Z`.
+pub const ORIGINAL_MTIME_ATTR: &str = "user.phototools.original_mtime";
+
+/// Copies the extended attributes of a file, including its ACLs, which Linux keeps in the
+/// `system.posix_acl_access` and `system.posix_acl_default` attributes. All attributes are
+/// tried: those that can't be set, such as `security.*` ones without privileges, are reported
+/// in the error afterwards.
+pub fn copy_all(from: &Path, to: &Path) -> io::Result<()> {
+    let mut failed = Vec::new();
+    let mut last_error = None;
+    for name in list(from)? {
+        let res = get(from, &name.to_string_lossy())
+            .and_then(|value| match value {
+                Some(v) => set(to, &name.to_string_lossy(), &v),
+                // Removed in the meantime
+                None => Ok(())
+            });
+        if let Err(e) = res {
+            failed.push(name.to_string_lossy().into_owned());
+            last_error = Some(e);
+        }
+    }
+    match last_error {
+        Some(e) => Err(io::Error::new(e.kind(), format!("unable to copy {}: {}", failed.join(", "), e))),
+        None => Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::{get, list, set};
+
+#[cfg(not(unix))]
+pub use self::unsupported::{get, list, set};
+
+// Symbolic links are followed, like when files are copied
+#[cfg(unix)]
+mod unix {
+    use std::ffi::OsString;
+    use std::io;
+    use std::path::Path;
+
+    /// The names of the extended attributes of a file.
+    pub fn list(p: &Path) -> io::Result<Vec<OsString>> {
+        Ok(::xattr::list_deref(p)?.collect())
+    }
+
+    /// The value of an extended attribute, if the file has it.
+    pub fn get(p: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+        ::xattr::get_deref(p, name)
+    }
+
+    /// Sets an extended attribute, replacing its value if the file has it.
+    pub fn set(p: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        ::xattr::set_deref(p, name, value)
+    }
+}
+
+#[cfg(not(unix))]
+mod unsupported {
+    use std::ffi::OsString;
+    use std::io;
+    use std::path::Path;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "extended attributes are not supported on this platform")
+    }
+
+    pub fn list(_p: &Path) -> io::Result<Vec<OsString>> {
+        Err(unsupported())
+    }
+
+    pub fn get(_p: &Path, _name: &str) -> io::Result<Option<Vec<u8>>> {
+        Err(unsupported())
+    }
+
+    pub fn set(_p: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testtools::get_target_dir;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_copy_all() {
+        let dir = PathBuf::from(get_target_dir() + "test_xattr");
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        let (from, to) = (dir.join("from.txt"), dir.join("to.txt"));
+        fs::write(&from, "from").unwrap();
+        fs::write(&to, "to").unwrap();
+
+        if let Err(e) = set(&from, "user.phototools.test", b"value") {
+            // Not every filesystem has extended attributes
+            println!("Skipping the test: {}", e);
+            return;
+        }
+        let long = vec![b'x'; 1000];
+        set(&from, "user.phototools.long", &long).unwrap();
+        assert_eq!(None, get(&to, "user.phototools.test").unwrap());
+
+        copy_all(&from, &to).unwrap();
+        assert_eq!(Some(b"value".to_vec()), get(&to, "user.phototools.test").unwrap());
+        assert_eq!(Some(long), get(&to, "user.phototools.long").unwrap());
+        let names: Vec<OsString> = list(&to).unwrap();
+        assert!(names.contains(&OsString::from("user.phototools.test")));
+    }
+}